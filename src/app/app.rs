@@ -14,6 +14,7 @@ use dotenvy::dotenv;
 use teo_runtime::connection::transaction;
 use crate::app::callbacks::callback::AsyncCallbackArgument;
 use crate::prelude::{Entrance, RuntimeVersion};
+use crate::server::options::ServerOptions;
 
 #[derive(Debug)]
 pub struct App { }
@@ -61,6 +62,13 @@ impl App {
         });
     }
 
+    pub fn on_shutdown<A, F>(&self, f: F) where F: AsyncCallbackArgument<A> + 'static {
+        let wrap_call = Box::leak(Box::new(f));
+        Ctx::set_shutdown(|ctx: transaction::Ctx| async {
+            wrap_call.call(ctx).await
+        });
+    }
+
     pub fn program<A, F>(&self, name: &str, f: F) where F: AsyncCallbackArgument<A> + 'static {
         let wrap_call = Box::leak(Box::new(f));
         Ctx::insert_program(name, |ctx: transaction::Ctx| async {
@@ -76,6 +84,14 @@ impl App {
         Ctx::main_namespace_mut()
     }
 
+    pub fn server_options(&self) -> &'static ServerOptions {
+        Ctx::server_options()
+    }
+
+    pub fn server_options_mut(&self) -> &'static mut ServerOptions {
+        Ctx::server_options_mut()
+    }
+
     pub async fn run(&self) -> Result<()> {
         self.prepare_for_run().await?;
         self.run_without_prepare().await
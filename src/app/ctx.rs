@@ -12,6 +12,7 @@ use crate::app::callbacks::callback::AsyncCallback;
 use crate::cli::command::CLI;
 use crate::cli::entrance::Entrance;
 use crate::cli::runtime_version::RuntimeVersion;
+use crate::server::options::ServerOptions;
 
 #[derive(Educe)]
 #[educe(Debug)]
@@ -27,9 +28,12 @@ pub struct Ctx {
     #[educe(Debug(ignore))]
     pub(crate) setup: Option<Arc<dyn AsyncCallback>>,
     #[educe(Debug(ignore))]
+    pub(crate) shutdown: Option<Arc<dyn AsyncCallback>>,
+    #[educe(Debug(ignore))]
     pub(crate) programs: BTreeMap<String, Arc<dyn AsyncCallback>>,
     #[educe(Debug(ignore))]
     pub(crate) conn_ctx: Option<connection::Ctx>,
+    pub(crate) server_options: ServerOptions,
 }
 
 impl Ctx {
@@ -44,8 +48,10 @@ impl Ctx {
             cli: None,
             schema: None,
             setup: None,
+            shutdown: None,
             programs: btreemap!{},
             conn_ctx: None,
+            server_options: ServerOptions::new(),
         }
     }
 
@@ -140,6 +146,14 @@ impl Ctx {
         Ctx::get().conn_ctx.as_ref().unwrap()
     }
 
+    pub fn server_options() -> &'static ServerOptions {
+        &Ctx::get().server_options
+    }
+
+    pub fn server_options_mut() -> &'static mut ServerOptions {
+        &mut Ctx::get_mut().server_options
+    }
+
     pub fn setup() -> Option<&'static Arc<dyn AsyncCallback>> {
         Ctx::get().setup.as_ref()
     }
@@ -148,6 +162,14 @@ impl Ctx {
         Ctx::get_mut().setup = Some(Arc::new(f));
     }
 
+    pub fn shutdown() -> Option<&'static Arc<dyn AsyncCallback>> {
+        Ctx::get().shutdown.as_ref()
+    }
+
+    pub fn set_shutdown<F>(f: F) where F: AsyncCallback + 'static {
+        Ctx::get_mut().shutdown = Some(Arc::new(f));
+    }
+
     pub fn insert_program<F>(name: &str, f: F) where F: AsyncCallback + 'static {
         Ctx::get_mut().programs.insert(name.to_owned(), Arc::new(f));
     }
@@ -11,6 +11,7 @@ use teo_mongodb_connector::connector::MongoDBConnection;
 use crate::app::ctx::Ctx;
 use teo_runtime::connection::Ctx as ConnCtx;
 use crate::message::info_message;
+use tracing::{info_span, Instrument};
 
 pub async fn connect_databases(namespace: &mut Namespace, silent: bool) -> Result<()> {
     may_connect_database(namespace, silent).await?;
@@ -22,10 +23,20 @@ pub async fn connect_databases(namespace: &mut Namespace, silent: bool) -> Resul
     Ok(())
 }
 
+pub async fn disconnect_databases(namespace: &mut Namespace) -> Result<()> {
+    Ctx::get_mut().conn_ctx = None;
+    namespace.connection = None;
+    for namespace in namespace.namespaces.values_mut() {
+        namespace.connection = None;
+    }
+    Ok(())
+}
+
 pub async fn may_connect_database(namespace: &mut Namespace, silent: bool) -> Result<()> {
     if namespace.connector.is_none() { return Ok(()) }
     let connector = namespace.connector.as_ref().unwrap();
-    let connection = connection_for_connector(connector).await;
+    let span = info_span!("connect", namespace = %namespace.path.join("."), provider = %connector.provider.lowercase_desc());
+    let connection = connection_for_connector(connector).instrument(span).await;
     if !silent {
         info_message(format!("{} connector connected for `{}` at \"{}\"", connector.provider.lowercase_desc(), if namespace.path.is_empty() { "main".to_string() } else { namespace.path().join(".") }, connector.url));
     }
@@ -3,6 +3,16 @@ pub(crate) struct ServeCommand {
     pub(crate) no_migration: bool,
     pub(crate) no_autoseed: bool,
     pub(crate) env: Option<String>,
+    pub(crate) tls_cert: Option<String>,
+    pub(crate) tls_key: Option<String>,
+    pub(crate) compression: bool,
+    pub(crate) compression_min_size: Option<usize>,
+    /// `PREFIX=DIR` pairs.
+    pub(crate) static_mounts: Vec<String>,
+    pub(crate) log_format: Option<String>,
+    pub(crate) log_file: Option<String>,
+    /// In milliseconds.
+    pub(crate) request_timeout: Option<u64>,
 }
 
 #[derive(Debug)]
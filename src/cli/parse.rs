@@ -56,7 +56,51 @@ pub(crate) fn parse(runtime_version: RuntimeVersion, entrance: Entrance, argv: O
                 .short('S')
                 .long("no-autoseed")
                 .help("Start server without auto seeding autoseed dataset")
-                .action(ArgAction::SetTrue)))
+                .action(ArgAction::SetTrue))
+            .arg(Arg::new("tls-cert")
+                .long("tls-cert")
+                .help("Serve HTTPS with the PEM certificate chain in this file")
+                .action(ArgAction::Set)
+                .requires("tls-key")
+                .num_args(1))
+            .arg(Arg::new("tls-key")
+                .long("tls-key")
+                .help("The PEM private key of the TLS certificate")
+                .action(ArgAction::Set)
+                .requires("tls-cert")
+                .num_args(1))
+            .arg(Arg::new("compression")
+                .long("compression")
+                .help("Compress responses")
+                .action(ArgAction::SetTrue))
+            .arg(Arg::new("compression-min-size")
+                .long("compression-min-size")
+                .help("Send smaller response bodies uncompressed, in bytes")
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(usize))
+                .num_args(1))
+            .arg(Arg::new("static")
+                .long("static")
+                .help("Serve a directory under a URL prefix, like `/assets=./public`")
+                .action(ArgAction::Append)
+                .num_args(1))
+            .arg(Arg::new("log-format")
+                .long("log-format")
+                .help("Output format of logs, `pretty` or `json`")
+                .action(ArgAction::Set)
+                .value_parser(["pretty", "json"])
+                .num_args(1))
+            .arg(Arg::new("log-file")
+                .long("log-file")
+                .help("Write logs to a rotating file instead of stdout")
+                .action(ArgAction::Set)
+                .num_args(1))
+            .arg(Arg::new("request-timeout")
+                .long("request-timeout")
+                .help("Answer requests which take longer than this many milliseconds with 504")
+                .action(ArgAction::Set)
+                .value_parser(clap::value_parser!(u64))
+                .num_args(1)))
         .subcommand(ClapCommand::new("generate")
             .about("Generate code")
             .arg_required_else_help(true)
@@ -142,7 +186,26 @@ pub(crate) fn parse(runtime_version: RuntimeVersion, entrance: Entrance, argv: O
     let command = match matches.subcommand() {
         Some(("serve", submatches)) => {
             let env: Option<&String> = submatches.get_one("ENV");
-            CLICommand::Serve(ServeCommand { no_migration: submatches.get_flag("no-migration"), no_autoseed: submatches.get_flag("no-autoseed"), env: env.cloned() })
+            let tls_cert: Option<&String> = submatches.get_one("tls-cert");
+            let tls_key: Option<&String> = submatches.get_one("tls-key");
+            let compression_min_size: Option<&usize> = submatches.get_one("compression-min-size");
+            let static_mounts: Vec<String> = submatches.get_many::<String>("static").map(|s| s.cloned().collect()).unwrap_or_default();
+            let log_format: Option<&String> = submatches.get_one("log-format");
+            let log_file: Option<&String> = submatches.get_one("log-file");
+            let request_timeout: Option<&u64> = submatches.get_one("request-timeout");
+            CLICommand::Serve(ServeCommand {
+                no_migration: submatches.get_flag("no-migration"),
+                no_autoseed: submatches.get_flag("no-autoseed"),
+                env: env.cloned(),
+                tls_cert: tls_cert.cloned(),
+                tls_key: tls_key.cloned(),
+                compression: submatches.get_flag("compression"),
+                compression_min_size: compression_min_size.cloned(),
+                static_mounts,
+                log_format: log_format.cloned(),
+                log_file: log_file.cloned(),
+                request_timeout: request_timeout.cloned(),
+            })
         }
         Some(("generate", submatches)) => {
             match submatches.subcommand() {
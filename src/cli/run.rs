@@ -1,8 +1,16 @@
+use std::time::Duration;
 use teo_result::{Error, Result};
 use crate::app::ctx::Ctx;
-use crate::app::database::connect_databases;
-use crate::cli::command::{CLI, CLICommand, GenerateCommand, SeedCommandAction};
+use crate::app::database::{connect_databases, disconnect_databases};
+use crate::cli::command::{CLI, CLICommand, GenerateCommand, SeedCommandAction, ServeCommand};
+use crate::server::health::set_ready;
 use crate::server::make::serve;
+use crate::server::options::ServerOptions;
+use crate::server::static_files::StaticMount;
+use crate::server::tls::{PemSource, Tls};
+use crate::server::subscription::observe_model_changes;
+use crate::message::{configure_logging, flush_logging};
+use crate::server::telemetry::{configure_tracing, shutdown_tracing};
 use teo_runtime::connection::transaction;
 use teo_runtime::schema::load::load_data_sets::load_data_sets;
 use crate::migrate::migrate;
@@ -12,6 +20,10 @@ use crate::seeder::seed::seed;
 pub async fn run(cli: &CLI) -> Result<()> {
     match &cli.command {
         CLICommand::Serve(serve_command) => {
+            apply_serve_flags(Ctx::server_options_mut(), serve_command)?;
+            configure_logging(&Ctx::server_options().logging, serve_command.log_format.as_deref(), serve_command.log_file.as_deref())?;
+            configure_tracing(&Ctx::server_options().telemetry)?;
+            observe_model_changes(Ctx::main_namespace_mut());
             connect_databases(Ctx::main_namespace_mut(), cli.silent).await?;
             let conn_ctx = Ctx::conn_ctx();
             // migrate
@@ -32,7 +44,17 @@ pub async fn run(cli: &CLI) -> Result<()> {
                 setup.call(transaction_ctx).await?;
             }
             // start server
-            serve(conn_ctx.namespace(), conn_ctx.namespace().server.as_ref().unwrap(), &Ctx::get().runtime_version, &Ctx::get().entrance, cli.silent).await
+            set_ready(true);
+            serve(conn_ctx.namespace(), conn_ctx.namespace().server.as_ref().unwrap(), Ctx::server_options(), &Ctx::get().runtime_version, &Ctx::get().entrance, cli.silent).await?;
+            // shutdown
+            if let Some(shutdown) = Ctx::shutdown() {
+                let transaction_ctx = transaction::Ctx::new(Ctx::conn_ctx().clone());
+                shutdown.call(transaction_ctx).await?;
+            }
+            disconnect_databases(Ctx::main_namespace_mut()).await?;
+            shutdown_tracing(&Ctx::server_options().telemetry);
+            flush_logging();
+            Ok(())
         }
         CLICommand::Generate(generate_command) => {
             match generate_command {
@@ -109,4 +131,28 @@ pub async fn run(cli: &CLI) -> Result<()> {
             }
         },
     }
-}
\ No newline at end of file
+}
+
+/// Flags of `serve` override the server options set by the app.
+fn apply_serve_flags(options: &mut ServerOptions, serve_command: &ServeCommand) -> Result<()> {
+    if let (Some(certificate), Some(private_key)) = (&serve_command.tls_cert, &serve_command.tls_key) {
+        // other TLS settings of the app are kept
+        let tls = options.tls.get_or_insert_with(|| Tls::new(PemSource::File(certificate.into()), PemSource::File(private_key.into())));
+        tls.certificate = PemSource::File(certificate.into());
+        tls.private_key = PemSource::File(private_key.into());
+    }
+    if serve_command.compression {
+        options.compression.enabled = true;
+    }
+    if let Some(min_size) = serve_command.compression_min_size {
+        options.compression.min_size = min_size;
+    }
+    for mount in &serve_command.static_mounts {
+        let (prefix, dir) = mount.split_once('=').ok_or_else(|| Error::new(format!("invalid static mount `{}`, expect `PREFIX=DIR`", mount)))?;
+        options.add_static_mount(StaticMount::new(prefix, dir));
+    }
+    if let Some(request_timeout) = serve_command.request_timeout {
+        options.request_timeout = Some(Duration::from_millis(request_timeout));
+    }
+    Ok(())
+}
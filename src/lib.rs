@@ -12,6 +12,11 @@ pub mod prelude {
     pub use crate::cli::entrance::Entrance;
     pub use crate::cli::runtime_version::RuntimeVersion;
     pub use crate::server::static_files::serve_static_files;
+    pub use crate::server::upload::persist_upload;
+    pub use crate::server::stream::{stream_response, sse_response, SseEvent};
+    pub use crate::server::subscription::{publish_model_change, ModelChangeKind};
+    pub use crate::server::request_id::RequestIdExt;
+    pub use crate::server::logging::set_log_identity;
     pub use teo_runtime::namespace::Namespace;
     pub extern crate teo_result;
     pub use teo_result::{Error, Result, ResultExt};
@@ -1,7 +1,86 @@
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::mpsc::{sync_channel, SyncSender};
+use std::sync::RwLock;
+use std::thread::JoinHandle;
 use std::time::Duration;
-use chrono::{DateTime, Local};
+use chrono::{DateTime, Local, SecondsFormat, Utc};
 use colored::{ColoredString, Colorize};
-use array_tool::vec::Join;
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde_json::{json, Value as JsonValue};
+use teo_result::{Error, Result};
+use crate::server::logging::{LogFormat, Logging};
+use crate::server::request_id::current_request_id;
+
+struct Sink {
+    format: LogFormat,
+    file: Option<FileWriter>,
+}
+
+/// Writes lines to a `RotatingFile` on a dedicated thread, so that workers don't wait for the
+/// disk. Workers only wait when `FILE_QUEUE_SIZE` lines are already queued.
+struct FileWriter {
+    sender: SyncSender<String>,
+    thread: JoinHandle<()>,
+}
+
+const FILE_QUEUE_SIZE: usize = 4096;
+
+impl FileWriter {
+
+    fn spawn(mut file: RotatingFile) -> Result<Self> {
+        let (sender, receiver) = sync_channel::<String>(FILE_QUEUE_SIZE);
+        let thread = std::thread::Builder::new().name("teo-log".to_owned()).spawn(move || {
+            for line in receiver {
+                file.write_line(&line);
+            }
+        }).map_err(|e| Error::new(format!("cannot start log writer: {}", e)))?;
+        Ok(Self { sender, thread })
+    }
+
+    fn write_line(&self, line: String) {
+        let _ = self.sender.send(line);
+    }
+
+    /// Wait until the queued lines are written.
+    fn close(self) {
+        drop(self.sender);
+        let _ = self.thread.join();
+    }
+}
+
+static SINK: Lazy<RwLock<Sink>> = Lazy::new(|| RwLock::new(Sink { format: LogFormat::Pretty, file: None }));
+
+static ANSI_ESCAPE: Lazy<Regex> = Lazy::new(|| Regex::new("\x1b\\[[0-9;]*m").unwrap());
+
+/// Apply the logging settings, `format` and `file` come from command line flags and take
+/// precedence over `logging`.
+pub(crate) fn configure_logging(logging: &Logging, format: Option<&str>, file: Option<&str>) -> Result<()> {
+    let format = match format {
+        Some(format) => LogFormat::from_name(format)?,
+        None => logging.format,
+    };
+    let path = file.map(PathBuf::from).or_else(|| logging.file.clone());
+    let file = match path {
+        Some(path) => Some(FileWriter::spawn(RotatingFile::open(path, logging.max_file_size, logging.max_files)?)?),
+        None => None,
+    };
+    let previous = std::mem::replace(&mut *SINK.write().unwrap(), Sink { format, file });
+    if let Some(file) = previous.file {
+        file.close();
+    }
+    Ok(())
+}
+
+/// Write out the lines which are still queued for the log file, later messages go to stdout.
+pub(crate) fn flush_logging() {
+    let file = SINK.write().unwrap().file.take();
+    if let Some(file) = file {
+        file.close();
+    }
+}
 
 fn timestamp() -> ColoredString {
     let local: DateTime<Local> = Local::now();
@@ -9,8 +88,27 @@ fn timestamp() -> ColoredString {
     local_formatted
 }
 
+fn emit(pretty: impl FnOnce() -> String, structured: impl FnOnce() -> JsonValue) {
+    let sink = SINK.read().unwrap();
+    let line = match sink.format {
+        LogFormat::Pretty => pretty(),
+        LogFormat::Json => {
+            let mut value = structured();
+            value["timestamp"] = json!(Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true));
+            value.to_string()
+        }
+    };
+    match &sink.file {
+        Some(file) => file.write_line(ANSI_ESCAPE.replace_all(&line, "").into_owned()),
+        None => println!("{}", line),
+    }
+}
+
 pub fn info_message(content: impl AsRef<str>) {
-    println!("{} {}", timestamp(), content.as_ref())
+    emit(
+        || format!("{} {}", timestamp(), content.as_ref()),
+        || json!({ "level": "info", "message": ANSI_ESCAPE.replace_all(content.as_ref(), "") }),
+    )
 }
 
 pub fn request_message(
@@ -20,12 +118,16 @@ pub fn request_message(
     handler_group_path: &Vec<String>,
     action: &str,
     code: u16,
+    request_id: Option<&str>,
+    identity: Option<&str>,
 ) {
     let handler_str: String = handler_group_path.join(".") + ".";
-    let code_string = format_code_into_string(code);
-    let ms = time_elapsed.as_millis();
-    let ms_str = format!("{ms}ms").normal().clear();
-    println!("{} {} {} => {}{} {} {}", timestamp(), method.bright_blue().bold(), path.bright_yellow(), handler_str.magenta(), action.purple(), code_string, ms_str)
+    emit(|| {
+        let code_string = format_code_into_string(code);
+        let ms = time_elapsed.as_millis();
+        let ms_str = format!("{ms}ms").normal().clear();
+        format!("{} {} {} => {}{} {} {}{}", timestamp(), method.bright_blue().bold(), path.bright_yellow(), handler_str.magenta(), action.purple(), code_string, ms_str, request_id_string(request_id))
+    }, || access_log(time_elapsed, method, path, Some(format!("{}{}", handler_str, action)), code, request_id, identity))
 }
 
 pub fn unhandled_request_message(
@@ -33,11 +135,49 @@ pub fn unhandled_request_message(
     method: &str,
     path: &str,
     code: u16,
+    request_id: Option<&str>,
+    identity: Option<&str>,
 ) {
-    let code_string = format_code_into_string(code);
-    let ms = time_elapsed.as_millis();
-    let ms_str = format!("{ms}ms").normal().clear();
-    println!("{} {} {} {} {}", timestamp(), method.bright_blue().bold(), path.bright_yellow(), code_string, ms_str)
+    emit(|| {
+        let code_string = format_code_into_string(code);
+        let ms = time_elapsed.as_millis();
+        let ms_str = format!("{ms}ms").normal().clear();
+        format!("{} {} {} {} {}{}", timestamp(), method.bright_blue().bold(), path.bright_yellow(), code_string, ms_str, request_id_string(request_id))
+    }, || access_log(time_elapsed, method, path, None, code, request_id, identity))
+}
+
+pub(crate) fn timeout_message(handler: &str, timeout: Duration) {
+    let request_id = current_request_id();
+    emit(|| {
+        format!("{} {} {}{}", timestamp(), handler.magenta(), format!("timed out after {}ms", timeout.as_millis()).red().bold(), request_id_string(request_id.as_deref()))
+    }, || json!({
+        "level": "warn",
+        "type": "timeout",
+        "handler": handler,
+        "timeoutMs": timeout.as_millis() as u64,
+        "requestId": request_id,
+    }))
+}
+
+fn access_log(time_elapsed: Duration, method: &str, path: &str, handler: Option<String>, code: u16, request_id: Option<&str>, identity: Option<&str>) -> JsonValue {
+    json!({
+        "level": "info",
+        "type": "request",
+        "method": method,
+        "path": path,
+        "handler": handler,
+        "status": code,
+        "durationMs": time_elapsed.as_secs_f64() * 1000.0,
+        "requestId": request_id,
+        "identity": identity,
+    })
+}
+
+fn request_id_string(request_id: Option<&str>) -> String {
+    match request_id {
+        Some(request_id) => format!(" {}", request_id.dimmed()),
+        None => "".to_owned(),
+    }
 }
 
 fn format_code_into_string(code: u16) -> ColoredString {
@@ -47,4 +187,124 @@ fn format_code_into_string(code: u16) -> ColoredString {
         300..=399 => code.to_string().yellow().bold(),
         _ => code.to_string().red().bold(),
     }
-}
\ No newline at end of file
+}
+
+/// A log file which is renamed to `path.1` once it reaches `max_size`, older files are
+/// shifted up to `path.{max_files}`.
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: u64,
+    max_files: usize,
+}
+
+impl RotatingFile {
+
+    fn open(path: PathBuf, max_size: u64, max_files: usize) -> Result<Self> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).map_err(|e| Error::new(format!("cannot create log directory: {}", e)))?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&path).map_err(|e| Error::new(format!("cannot open log file {}: {}", path.display(), e)))?;
+        let size = file.metadata().map(|m| m.len()).unwrap_or(0);
+        Ok(Self { path, file, size, max_size, max_files })
+    }
+
+    fn write_line(&mut self, line: &str) {
+        let len = line.len() as u64 + 1;
+        if self.size > 0 && self.size + len > self.max_size {
+            self.rotate();
+        }
+        if writeln!(self.file, "{}", line).is_ok() {
+            self.size += len;
+        }
+    }
+
+    fn rotate(&mut self) {
+        let rotated = |index: usize| {
+            let mut path = self.path.clone().into_os_string();
+            path.push(format!(".{}", index));
+            PathBuf::from(path)
+        };
+        if self.max_files == 0 {
+            let _ = self.file.set_len(0);
+            self.size = 0;
+            return;
+        }
+        for index in (1..self.max_files).rev() {
+            let _ = std::fs::rename(rotated(index), rotated(index + 1));
+        }
+        let _ = std::fs::rename(&self.path, rotated(1));
+        if let Ok(file) = OpenOptions::new().create(true).append(true).open(&self.path) {
+            self.file = file;
+            self.size = 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use super::{FileWriter, RotatingFile};
+
+    fn log_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("teo-log-{}", uuid::Uuid::new_v4()))
+    }
+
+    fn read(path: &PathBuf, suffix: &str) -> Option<String> {
+        let mut path = path.clone().into_os_string();
+        path.push(suffix);
+        std::fs::read_to_string(PathBuf::from(path)).ok()
+    }
+
+    #[test]
+    fn files_are_rotated_at_max_size() {
+        let dir = log_dir();
+        let path = dir.join("access.log");
+        let mut file = RotatingFile::open(path.clone(), 10, 2).unwrap();
+        file.write_line("first");
+        file.write_line("second");
+        file.write_line("third");
+        assert_eq!(read(&path, "").as_deref(), Some("third\n"));
+        assert_eq!(read(&path, ".1").as_deref(), Some("second\n"));
+        assert_eq!(read(&path, ".2").as_deref(), Some("first\n"));
+        file.write_line("fourth");
+        assert_eq!(read(&path, ".2").as_deref(), Some("second\n"));
+        assert!(read(&path, ".3").is_none());
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn files_are_truncated_without_backups() {
+        let dir = log_dir();
+        let path = dir.join("access.log");
+        let mut file = RotatingFile::open(path.clone(), 10, 0).unwrap();
+        file.write_line("first");
+        file.write_line("second");
+        assert_eq!(read(&path, "").as_deref(), Some("second\n"));
+        assert!(read(&path, ".1").is_none());
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn existing_files_count_towards_the_size() {
+        let dir = log_dir();
+        let path = dir.join("access.log");
+        RotatingFile::open(path.clone(), 10, 1).unwrap().write_line("first");
+        RotatingFile::open(path.clone(), 10, 1).unwrap().write_line("second");
+        assert_eq!(read(&path, ".1").as_deref(), Some("first\n"));
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn queued_lines_are_written_on_close() {
+        let dir = log_dir();
+        let path = dir.join("access.log");
+        let writer = FileWriter::spawn(RotatingFile::open(path.clone(), 1024, 1).unwrap()).unwrap();
+        writer.write_line("first".to_owned());
+        writer.write_line("second".to_owned());
+        writer.close();
+        assert_eq!(read(&path, "").as_deref(), Some("first\nsecond\n"));
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,185 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::future::Future;
+use serde_json::{Value as JsonValue};
+use teo_result::Result;
+use teo_runtime::action::Action;
+use teo_runtime::handler::action::builtin_action_handler_from_name;
+use teo_runtime::handler::default::{create, find_first, find_many, find_unique, update, upsert, copy, create_many, update_many, copy_many, delete_many, count, aggregate, group_by, delete};
+use teo_runtime::handler::input::validate_and_transform_json_input_for_builtin_action;
+use teo_runtime::middleware::next::Next;
+use teo_runtime::model::Model;
+use teo_runtime::namespace::Namespace;
+use teo_runtime::request;
+use teo_runtime::response::Response;
+use teo_teon::Value;
+use tracing::{info_span, Instrument};
+use crate::server::encoding::json_to_teon;
+use crate::server::rate_limit::IdentityLimit;
+
+/// An action which is available on every model, like `findMany` or `create`.
+#[derive(Clone)]
+pub struct ModelAction {
+    name: String,
+    /// The runtime action whose input rules validate requests. Application defined actions
+    /// don't have one and receive the request body as is.
+    action: Option<Action>,
+    call: &'static dyn Next,
+}
+
+impl ModelAction {
+
+    pub fn name(&self) -> &str {
+        self.name.as_str()
+    }
+
+    pub(crate) fn transform_input(&self, model: &Model, json_body: &JsonValue, main_namespace: &Namespace) -> Result<Value> {
+        match self.action {
+            Some(action) => validate_and_transform_json_input_for_builtin_action(model, action, json_body, main_namespace),
+            None => Ok(json_to_teon(json_body.clone())),
+        }
+    }
+
+    /// Run the action through the middlewares of `namespace`. The identity limit is checked
+    /// after the middlewares. The handler span is created before the middlewares span is entered
+    /// so that they are siblings.
+    pub(crate) async fn call(&self, namespace: &'static Namespace, ctx: request::Ctx, identity_limit: Option<IdentityLimit>) -> Result<Response> {
+        let call = self.call;
+        let span = info_span!("handler", action = self.name.as_str());
+        namespace.middleware_stack.call(ctx, &move |ctx: request::Ctx| {
+            let identity_limit = identity_limit.clone();
+            async move {
+                if let Some(identity_limit) = identity_limit {
+                    identity_limit.check().await?;
+                }
+                call.call(ctx).await
+            }.instrument(span.clone())
+        }).instrument(info_span!("middlewares")).await
+    }
+}
+
+/// The model actions served by the server. It starts with the builtin actions, applications
+/// add their own and disable actions for specific models, keyed by the dot joined model path.
+#[derive(Clone)]
+pub struct ModelActionRegistry {
+    actions: BTreeMap<String, ModelAction>,
+    disabled: BTreeMap<String, BTreeSet<String>>,
+}
+
+impl Default for ModelActionRegistry {
+
+    fn default() -> Self {
+        let mut registry = Self {
+            actions: BTreeMap::new(),
+            disabled: BTreeMap::new(),
+        };
+        registry.insert_builtin("findMany", |ctx: request::Ctx| async move { find_many(&ctx).await });
+        registry.insert_builtin("findFirst", |ctx: request::Ctx| async move { find_first(&ctx).await });
+        registry.insert_builtin("findUnique", |ctx: request::Ctx| async move { find_unique(&ctx).await });
+        registry.insert_builtin("create", |ctx: request::Ctx| async move { create(&ctx).await });
+        registry.insert_builtin("delete", |ctx: request::Ctx| async move { delete(&ctx).await });
+        registry.insert_builtin("update", |ctx: request::Ctx| async move { update(&ctx).await });
+        registry.insert_builtin("upsert", |ctx: request::Ctx| async move { upsert(&ctx).await });
+        registry.insert_builtin("copy", |ctx: request::Ctx| async move { copy(&ctx).await });
+        registry.insert_builtin("createMany", |ctx: request::Ctx| async move { create_many(&ctx).await });
+        registry.insert_builtin("updateMany", |ctx: request::Ctx| async move { update_many(&ctx).await });
+        registry.insert_builtin("copyMany", |ctx: request::Ctx| async move { copy_many(&ctx).await });
+        registry.insert_builtin("deleteMany", |ctx: request::Ctx| async move { delete_many(&ctx).await });
+        registry.insert_builtin("count", |ctx: request::Ctx| async move { count(&ctx).await });
+        registry.insert_builtin("aggregate", |ctx: request::Ctx| async move { aggregate(&ctx).await });
+        registry.insert_builtin("groupBy", |ctx: request::Ctx| async move { group_by(&ctx).await });
+        registry
+    }
+}
+
+impl ModelActionRegistry {
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add an action to every model. The model is found from the handler match of the request
+    /// context. Registering a builtin name replaces the builtin action.
+    pub fn register<F, Fut>(&mut self, name: &str, call: F) where
+        F: Fn(request::Ctx) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<Response>> + Send + 'static {
+        self.insert(name, None, call);
+    }
+
+    /// Make an action unavailable for a model, requests to it receive 404.
+    pub fn disable(&mut self, model_path: &str, name: &str) {
+        self.disabled.entry(model_path.to_owned()).or_insert_with(BTreeSet::new).insert(name.to_owned());
+    }
+
+    pub fn enable(&mut self, model_path: &str, name: &str) {
+        if let Some(disabled) = self.disabled.get_mut(model_path) {
+            disabled.remove(name);
+        }
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.actions.keys().map(|k| k.as_str()).collect()
+    }
+
+    pub(crate) fn get(&self, model_path: &Vec<String>, name: &str) -> Option<&ModelAction> {
+        if let Some(disabled) = self.disabled.get(&model_path.join(".")) {
+            if disabled.contains(name) {
+                return None;
+            }
+        }
+        self.actions.get(name)
+    }
+
+    fn insert_builtin<F, Fut>(&mut self, name: &str, call: F) where
+        F: Fn(request::Ctx) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<Response>> + Send + 'static {
+        self.insert(name, builtin_action_handler_from_name(name), call);
+    }
+
+    fn insert<F, Fut>(&mut self, name: &str, action: Option<Action>, call: F) where
+        F: Fn(request::Ctx) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<Response>> + Send + 'static {
+        // registrations happen once at startup, the middleware stack needs a 'static reference
+        let call: &'static dyn Next = Box::leak(Box::new(call));
+        self.actions.insert(name.to_owned(), ModelAction { name: name.to_owned(), action, call });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use teo_runtime::request;
+    use teo_runtime::response::Response;
+    use super::ModelActionRegistry;
+
+    fn path(model_path: &str) -> Vec<String> {
+        model_path.split('.').map(|s| s.to_owned()).collect()
+    }
+
+    #[test]
+    fn builtin_actions_are_registered() {
+        let registry = ModelActionRegistry::new();
+        for name in ["findMany", "findUnique", "create", "update", "delete", "groupBy"] {
+            assert!(registry.get(&path("User"), name).is_some(), "{} is missing", name);
+        }
+        assert!(registry.get(&path("User"), "publish").is_none());
+    }
+
+    #[test]
+    fn registered_actions_are_available_without_input_rules() {
+        let mut registry = ModelActionRegistry::new();
+        registry.register("publish", |_ctx: request::Ctx| async move { Ok(Response::empty()) });
+        let action = registry.get(&path("admin.Post"), "publish").unwrap();
+        assert_eq!(action.name(), "publish");
+        assert!(action.action.is_none());
+        assert!(registry.names().contains(&"publish"));
+    }
+
+    #[test]
+    fn actions_are_disabled_per_model() {
+        let mut registry = ModelActionRegistry::new();
+        registry.disable("admin.User", "delete");
+        assert!(registry.get(&path("admin.User"), "delete").is_none());
+        assert!(registry.get(&path("User"), "delete").is_some());
+        registry.enable("admin.User", "delete");
+        assert!(registry.get(&path("admin.User"), "delete").is_some());
+    }
+}
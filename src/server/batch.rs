@@ -0,0 +1,177 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::Instant;
+use actix_http::StatusCode;
+use actix_web::{HttpRequest, HttpResponse};
+use serde_json::{json, Value as JsonValue};
+use teo_result::{Error, Result};
+use teo_runtime::connection::transaction;
+use teo_runtime::error_runtime_ext::ErrorRuntimeExt;
+use teo_runtime::handler::handler::Method;
+use teo_runtime::handler::r#match::HandlerMatch;
+use teo_runtime::namespace::Namespace;
+use teo_runtime::response::body::BodyInner;
+use teo_runtime::response::Response;
+use teo_runtime::{connection, request};
+use teo_teon::Value;
+use tracing::{info_span, Instrument};
+use crate::server::actions::ModelAction;
+use crate::server::error::{error_body, status_error};
+use crate::server::options::ServerOptions;
+use crate::server::rate_limit::{limit_batch, IdentityLimit};
+use crate::server::request::RequestImpl;
+use crate::server::responder::IntoHttpResponse;
+use crate::server::subscription::{hold_changes, publish_changes};
+use crate::server::timeout::with_timeout;
+
+struct Operation {
+    namespace: &'static Namespace,
+    action: &'static ModelAction,
+    handler_match: HandlerMatch,
+    body: Value,
+}
+
+impl Operation {
+
+    fn handler_path(&self) -> Vec<String> {
+        let mut path = self.handler_match.path.clone();
+        path.push(self.handler_match.name.clone());
+        path
+    }
+}
+
+/// Run the operations of `{"operations": [{"model": "User", "action": "create", "args": {...}}]}`
+/// in one transaction. The results are returned in order, or the error of the first failing
+/// operation with its `index` after everything is rolled back. When committing fails, the
+/// error has `commit` set instead of an index. Each operation counts against
+/// the rate and concurrency limits of the handler it runs.
+pub(crate) async fn handle_batch(main_namespace: &'static Namespace, options: &'static ServerOptions, http_request: &HttpRequest, json_body: JsonValue, started: Instant) -> HttpResponse {
+    let operations = match json_body.get("operations").and_then(|o| o.as_array()) {
+        Some(operations) => operations,
+        None => return error_response(Error::value_error_message_only("expect `operations` array"), None),
+    };
+    if operations.len() > options.batch_max_operations {
+        return error_response(status_error(413, format!("batch has more than {} operations", options.batch_max_operations)), None);
+    }
+    let mut resolved = vec![];
+    for (index, operation) in operations.iter().enumerate() {
+        match resolve_operation(main_namespace, options, operation) {
+            Ok(operation) => resolved.push(operation),
+            Err(err) => return error_response(err, Some(index)),
+        }
+    }
+    let handler_paths: Vec<Vec<String>> = resolved.iter().map(|operation| operation.handler_path()).collect();
+    let _permits = match limit_batch(options, &handler_paths, http_request).await {
+        Ok(permits) => permits,
+        Err(response) => return response,
+    };
+    let identity_limits: Arc<Vec<Option<IdentityLimit>>> = Arc::new(handler_paths.iter().map(|handler_path| IdentityLimit::new(options, handler_path, http_request)).collect());
+    let resolved = Arc::new(resolved);
+    let current = Arc::new(AtomicUsize::new(0));
+    let finished = Arc::new(AtomicBool::new(false));
+    let conn_ctx = connection::Ctx::from_namespace(main_namespace);
+    let batch_transaction_ctx = transaction::Ctx::new(conn_ctx);
+    let (result, changes) = hold_changes(with_timeout(options, &vec!["batch".to_owned()], started, Some(&batch_transaction_ctx), batch_transaction_ctx.run_transaction(|transaction_ctx: transaction::Ctx| {
+        let resolved = resolved.clone();
+        let identity_limits = identity_limits.clone();
+        let current = current.clone();
+        let finished = finished.clone();
+        let http_request = http_request.clone();
+        async move {
+            let mut responses = vec![];
+            for (index, operation) in resolved.iter().enumerate() {
+                current.store(index, Ordering::SeqCst);
+                let ctx = request::Ctx::new(
+                    request::Request::new(Arc::new(RequestImpl::new(http_request.clone()))),
+                    Arc::new(operation.body.clone()),
+                    transaction_ctx.clone(),
+                    operation.handler_match.clone(),
+                );
+                let response = operation.action.call(operation.namespace, ctx, identity_limits[index].clone()).await?;
+                if response.code() >= 400 {
+                    Err(status_error(response.code(), format!("operation returned status {}", response.code())))?
+                }
+                responses.push(response);
+            }
+            finished.store(true, Ordering::SeqCst);
+            Ok(responses)
+        }
+    }).instrument(info_span!("transaction", operations = resolved.len())))).await;
+    if let Some(response) = identity_limits.iter().flatten().find_map(|limit| limit.rejected_response()) {
+        return response;
+    }
+    match result {
+        Ok(responses) => {
+            // changes are only visible to subscribers after the transaction is committed
+            publish_changes(changes);
+            let results = responses.iter().map(|response| match response.body().inner.as_ref() {
+                BodyInner::Teon(value) => value.clone(),
+                _ => Value::Null,
+            }).collect();
+            Response::data(Value::Array(results)).into_http_response(http_request.clone())
+        }
+        // every operation succeeded, the transaction couldn't be committed
+        Err(err) if finished.load(Ordering::SeqCst) => commit_error_response(err),
+        Err(err) => error_response(err, Some(current.load(Ordering::SeqCst))),
+    }
+}
+
+fn resolve_operation(main_namespace: &'static Namespace, options: &'static ServerOptions, operation: &JsonValue) -> Result<Operation> {
+    let model_path: Vec<&str> = operation.get("model").and_then(|m| m.as_str()).ok_or_else(|| Error::value_error_message_only("operation model is missing"))?.split('.').collect();
+    let action_name = operation.get("action").and_then(|a| a.as_str()).ok_or_else(|| Error::value_error_message_only("operation action is missing"))?;
+    let args = operation.get("args").cloned().unwrap_or(json!({}));
+    let model = main_namespace.model_at_path(&model_path).ok_or_else(|| Error::value_error_message_only(format!("model `{}` is not found", model_path.join("."))))?;
+    let model_path_strings: Vec<String> = model_path.iter().map(|s| s.to_string()).collect();
+    let action = options.model_actions.get(&model_path_strings, action_name).ok_or_else(|| Error::value_error_message_only(format!("`{}` is not a model action", action_name)))?;
+    let namespace_path = model_path_strings[0..model_path_strings.len() - 1].to_vec();
+    let namespace = main_namespace.namespace_at_path(&namespace_path).ok_or_else(|| Error::not_found_message_only())?;
+    let handler_match = main_namespace.handler_map.default_match(Method::Post, &format!("/{}/{}", model_path.join("/"), action_name)).ok_or_else(|| Error::not_found_message_only())?;
+    let body = action.transform_input(model, &args, main_namespace)?;
+    Ok(Operation { namespace, action, handler_match, body })
+}
+
+fn error_response(error: Error, index: Option<usize>) -> HttpResponse {
+    let mut body = error_body(&error);
+    body["index"] = json!(index);
+    HttpResponse::Ok().status(StatusCode::from_u16(error.code.unwrap_or(500)).unwrap()).json(body)
+}
+
+fn commit_error_response(error: Error) -> HttpResponse {
+    let mut body = error_body(&error);
+    body["commit"] = json!(true);
+    HttpResponse::Ok().status(StatusCode::from_u16(error.code.unwrap_or(500)).unwrap()).json(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_http::StatusCode;
+    use actix_web::body::MessageBody;
+    use serde_json::Value as JsonValue;
+    use crate::server::error::status_error;
+    use super::{commit_error_response, error_response};
+
+    fn body(response: actix_web::HttpResponse) -> JsonValue {
+        serde_json::from_slice(&response.into_body().try_into_bytes().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn errors_carry_the_index_of_the_failed_operation() {
+        let response = error_response(status_error(404, "not found"), Some(2));
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(body(response)["index"], 2);
+    }
+
+    #[test]
+    fn malformed_batches_have_no_index() {
+        let response = error_response(status_error(400, "expect `operations` array"), None);
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert!(body(response)["index"].is_null());
+    }
+
+    #[test]
+    fn commit_failures_are_not_blamed_on_an_operation() {
+        let body = body(commit_error_response(status_error(500, "database is locked")));
+        assert_eq!(body["commit"], true);
+        assert!(body.get("index").is_none());
+    }
+}
@@ -0,0 +1,203 @@
+use actix_http::body::BodySize;
+use actix_http::header::{ContentEncoding, HeaderMap, HeaderValue, CONTENT_ENCODING, CONTENT_RANGE, CONTENT_TYPE, VARY};
+use actix_http::StatusCode;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompressionAlgorithm {
+    Brotli,
+    Gzip,
+    Zstd,
+}
+
+impl CompressionAlgorithm {
+
+    fn token(&self) -> &'static str {
+        match self {
+            CompressionAlgorithm::Brotli => "br",
+            CompressionAlgorithm::Gzip => "gzip",
+            CompressionAlgorithm::Zstd => "zstd",
+        }
+    }
+
+    fn content_encoding(&self) -> ContentEncoding {
+        match self {
+            CompressionAlgorithm::Brotli => ContentEncoding::Brotli,
+            CompressionAlgorithm::Gzip => ContentEncoding::Gzip,
+            CompressionAlgorithm::Zstd => ContentEncoding::Zstd,
+        }
+    }
+}
+
+/// Response compression. It's disabled by default, set `enabled` or pass `--compression` to
+/// `serve` to turn it on.
+#[derive(Debug, Clone)]
+pub struct Compression {
+    pub enabled: bool,
+    /// Supported algorithms in order of preference, used when a client accepts several of them
+    /// with the same quality.
+    pub algorithms: Vec<CompressionAlgorithm>,
+    /// Bodies smaller than this many bytes are sent as is. Streamed bodies have no known size
+    /// and are compressed regardless.
+    pub min_size: usize,
+    /// Compressed content types. An entry ending with `/*` matches a whole type. Files in
+    /// archive, image and video formats are already compressed and shouldn't be listed.
+    pub content_types: Vec<String>,
+}
+
+impl Default for Compression {
+
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            algorithms: vec![CompressionAlgorithm::Brotli, CompressionAlgorithm::Zstd, CompressionAlgorithm::Gzip],
+            min_size: 1024,
+            content_types: vec![
+                "application/json".to_owned(),
+                "application/msgpack".to_owned(),
+                "application/cbor".to_owned(),
+                "application/javascript".to_owned(),
+                "application/xml".to_owned(),
+                "image/svg+xml".to_owned(),
+                "text/html".to_owned(),
+                "text/css".to_owned(),
+                "text/plain".to_owned(),
+                "text/javascript".to_owned(),
+                "text/csv".to_owned(),
+                "text/xml".to_owned(),
+            ],
+        }
+    }
+}
+
+impl Compression {
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The encoding for a response. `Identity` leaves the body untouched. Partial content is
+    /// never compressed since its range refers to the uncompressed representation.
+    pub(crate) fn encoding_for(&self, accept_encoding: Option<&str>, status: StatusCode, response_headers: &mut HeaderMap, body_size: BodySize) -> ContentEncoding {
+        if !self.enabled || response_headers.contains_key(CONTENT_ENCODING) {
+            return ContentEncoding::Identity;
+        }
+        if status == StatusCode::PARTIAL_CONTENT || response_headers.contains_key(CONTENT_RANGE) {
+            return ContentEncoding::Identity;
+        }
+        match body_size {
+            BodySize::None => return ContentEncoding::Identity,
+            BodySize::Sized(size) if (size as usize) < self.min_size => return ContentEncoding::Identity,
+            _ => (),
+        }
+        let content_type = response_headers.get(CONTENT_TYPE).and_then(|c| c.to_str().ok()).unwrap_or("");
+        if !self.compresses_content_type(content_type) {
+            return ContentEncoding::Identity;
+        }
+        match self.negotiate(accept_encoding.unwrap_or("")) {
+            // the encoder adds `Vary` for compressed responses
+            Some(algorithm) => algorithm.content_encoding(),
+            None => {
+                response_headers.append(VARY, HeaderValue::from_static("Accept-Encoding"));
+                ContentEncoding::Identity
+            }
+        }
+    }
+
+    fn compresses_content_type(&self, content_type: &str) -> bool {
+        let mime = content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+        if mime.is_empty() {
+            return false;
+        }
+        self.content_types.iter().any(|allowed| match allowed.strip_suffix("/*") {
+            Some(prefix) => mime.split('/').next() == Some(prefix),
+            None => allowed.eq_ignore_ascii_case(&mime),
+        })
+    }
+
+    fn negotiate(&self, accept_encoding: &str) -> Option<CompressionAlgorithm> {
+        let mut result = None;
+        let mut result_quality = 0.0f32;
+        for algorithm in &self.algorithms {
+            // an explicitly listed algorithm takes precedence over `*`
+            let quality = accept_encoding_quality(accept_encoding, algorithm.token())
+                .or_else(|| accept_encoding_quality(accept_encoding, "*"))
+                .unwrap_or(0.0);
+            if quality > result_quality {
+                result = Some(*algorithm);
+                result_quality = quality;
+            }
+        }
+        result
+    }
+}
+
+pub(crate) fn accept_encoding_quality(accept_encoding: &str, token: &str) -> Option<f32> {
+    accept_encoding.split(',').find_map(|item| {
+        let mut parts = item.split(';');
+        if !parts.next().unwrap_or("").trim().eq_ignore_ascii_case(token) {
+            return None;
+        }
+        Some(parts
+            .filter_map(|p| p.trim().strip_prefix("q="))
+            .next()
+            .and_then(|q| q.parse::<f32>().ok())
+            .unwrap_or(1.0))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_http::body::BodySize;
+    use actix_http::header::{ContentEncoding, HeaderMap, HeaderValue, CONTENT_RANGE, CONTENT_TYPE, VARY};
+    use actix_http::StatusCode;
+    use super::{Compression, CompressionAlgorithm};
+
+    fn enabled() -> Compression {
+        Compression { enabled: true, ..Compression::new() }
+    }
+
+    fn json_headers() -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        headers
+    }
+
+    #[test]
+    fn negotiation_follows_quality_then_preference() {
+        let compression = enabled();
+        assert_eq!(compression.negotiate("gzip, br"), Some(CompressionAlgorithm::Brotli));
+        assert_eq!(compression.negotiate("gzip;q=1, br;q=0.5"), Some(CompressionAlgorithm::Gzip));
+        assert_eq!(compression.negotiate("br;q=0, *"), Some(CompressionAlgorithm::Zstd));
+        assert_eq!(compression.negotiate("identity"), None);
+        assert_eq!(compression.negotiate(""), None);
+    }
+
+    #[test]
+    fn small_bodies_and_other_types_are_not_compressed() {
+        let compression = enabled();
+        assert_eq!(compression.encoding_for(Some("gzip"), StatusCode::OK, &mut json_headers(), BodySize::Sized(10)), ContentEncoding::Identity);
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("image/png"));
+        assert_eq!(compression.encoding_for(Some("gzip"), StatusCode::OK, &mut headers, BodySize::Sized(4096)), ContentEncoding::Identity);
+        assert_eq!(Compression::default().encoding_for(Some("gzip"), StatusCode::OK, &mut json_headers(), BodySize::Sized(4096)), ContentEncoding::Identity);
+    }
+
+    #[test]
+    fn large_bodies_are_compressed() {
+        let compression = enabled();
+        assert_eq!(compression.encoding_for(Some("gzip"), StatusCode::OK, &mut json_headers(), BodySize::Sized(4096)), ContentEncoding::Gzip);
+        assert_eq!(compression.encoding_for(Some("gzip"), StatusCode::OK, &mut json_headers(), BodySize::Stream), ContentEncoding::Gzip);
+        let mut headers = json_headers();
+        assert_eq!(compression.encoding_for(Some("identity"), StatusCode::OK, &mut headers, BodySize::Sized(4096)), ContentEncoding::Identity);
+        assert_eq!(headers.get(VARY).unwrap(), "Accept-Encoding");
+    }
+
+    #[test]
+    fn partial_content_is_not_compressed() {
+        let compression = enabled();
+        assert_eq!(compression.encoding_for(Some("gzip"), StatusCode::PARTIAL_CONTENT, &mut json_headers(), BodySize::Sized(4096)), ContentEncoding::Identity);
+        let mut headers = json_headers();
+        headers.insert(CONTENT_RANGE, HeaderValue::from_static("bytes */4096"));
+        assert_eq!(compression.encoding_for(Some("gzip"), StatusCode::RANGE_NOT_SATISFIABLE, &mut headers, BodySize::Sized(4096)), ContentEncoding::Identity);
+    }
+}
@@ -0,0 +1,238 @@
+use actix_http::header::{HeaderMap, HeaderValue, ACCESS_CONTROL_ALLOW_CREDENTIALS, ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_EXPOSE_HEADERS, ACCESS_CONTROL_MAX_AGE, ACCESS_CONTROL_REQUEST_HEADERS, ACCESS_CONTROL_REQUEST_METHOD, ORIGIN, VARY};
+use actix_http::Method as HttpMethod;
+use regex::Regex;
+use teo_result::{Error, Result};
+
+#[derive(Debug, Clone)]
+pub enum AllowedOrigins {
+    Any,
+    List(Vec<String>),
+    Regex(Regex),
+}
+
+#[derive(Debug, Clone)]
+pub struct Cors {
+    pub allowed_origins: AllowedOrigins,
+    /// Headers accepted in preflight requests. When `None`, the requested headers are echoed back.
+    pub allowed_headers: Option<Vec<String>>,
+    pub exposed_headers: Vec<String>,
+    /// Only allowed with a list or a regex of origins, browsers would otherwise send
+    /// credentials to the server from any site.
+    pub allow_credentials: bool,
+    pub max_age: Option<u32>,
+}
+
+impl Default for Cors {
+
+    fn default() -> Self {
+        Self {
+            allowed_origins: AllowedOrigins::Any,
+            allowed_headers: None,
+            exposed_headers: vec![],
+            allow_credentials: false,
+            max_age: Some(86400),
+        }
+    }
+}
+
+impl Cors {
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn allow_origins(origins: Vec<String>) -> Self {
+        Self {
+            allowed_origins: AllowedOrigins::List(origins),
+            ..Self::default()
+        }
+    }
+
+    pub fn allow_origin_regex(regex: Regex) -> Self {
+        Self {
+            allowed_origins: AllowedOrigins::Regex(regex),
+            ..Self::default()
+        }
+    }
+
+    /// Credentials are refused for any origin, the server is not started with such a policy.
+    pub(crate) fn validate(&self) -> Result<()> {
+        if self.allow_credentials && matches!(self.allowed_origins, AllowedOrigins::Any) {
+            Err(Error::new("CORS credentials require a list or a regex of allowed origins"))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Whether requests from `origin` are allowed. Requests without an origin don't come from
+    /// browsers and are always allowed.
+    pub(crate) fn allows_origin(&self, origin: Option<&str>) -> bool {
+        match origin {
+            Some(origin) => match &self.allowed_origins {
+                AllowedOrigins::Any => true,
+                AllowedOrigins::List(list) => list.iter().any(|l| l == origin),
+                AllowedOrigins::Regex(regex) => regex.is_match(origin),
+            },
+            None => true,
+        }
+    }
+
+    fn allowed_origin(&self, origin: Option<&str>) -> Option<String> {
+        match &self.allowed_origins {
+            AllowedOrigins::Any => Some("*".to_owned()),
+            AllowedOrigins::List(list) => origin.filter(|o| list.iter().any(|l| l == o)).map(|o| o.to_owned()),
+            AllowedOrigins::Regex(regex) => origin.filter(|o| regex.is_match(o)).map(|o| o.to_owned()),
+        }
+    }
+
+    /// Write the CORS headers for a response. `allowed_methods` is the comma separated list of
+    /// methods registered for the requested path, it's only used to answer preflight requests.
+    pub(crate) fn apply(&self, method: &HttpMethod, request_headers: &HeaderMap, response_headers: &mut HeaderMap, allowed_methods: Option<&str>) {
+        let origin = request_headers.get(ORIGIN).and_then(|o| o.to_str().ok());
+        // responses differ by origin unless it's a wildcard, caches have to know even when
+        // this origin gets no CORS headers
+        if !matches!(self.allowed_origins, AllowedOrigins::Any) {
+            response_headers.append(VARY, HeaderValue::from_static("Origin"));
+        }
+        let allowed_origin = match self.allowed_origin(origin) {
+            Some(allowed_origin) => allowed_origin,
+            None => return,
+        };
+        let allowed_origin = match HeaderValue::from_str(&allowed_origin) {
+            Ok(allowed_origin) => allowed_origin,
+            Err(_) => return,
+        };
+        response_headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, allowed_origin);
+        if self.allow_credentials && allowed_origin != "*" {
+            response_headers.insert(ACCESS_CONTROL_ALLOW_CREDENTIALS, HeaderValue::from_static("true"));
+        }
+        let preflight = method == HttpMethod::OPTIONS && request_headers.contains_key(ACCESS_CONTROL_REQUEST_METHOD);
+        if !preflight {
+            if !self.exposed_headers.is_empty() {
+                if let Ok(value) = HeaderValue::from_str(&self.exposed_headers.join(", ")) {
+                    response_headers.insert(ACCESS_CONTROL_EXPOSE_HEADERS, value);
+                }
+            }
+            return
+        }
+        if let Some(allowed_methods) = allowed_methods {
+            if let Ok(value) = HeaderValue::from_str(allowed_methods) {
+                response_headers.insert(ACCESS_CONTROL_ALLOW_METHODS, value);
+            }
+        }
+        let allowed_headers = match &self.allowed_headers {
+            Some(headers) => Some(headers.join(", ")),
+            None => request_headers.get(ACCESS_CONTROL_REQUEST_HEADERS).and_then(|h| h.to_str().ok()).map(|h| h.to_owned()),
+        };
+        if let Some(allowed_headers) = allowed_headers {
+            if let Ok(value) = HeaderValue::from_str(&allowed_headers) {
+                response_headers.insert(ACCESS_CONTROL_ALLOW_HEADERS, value);
+            }
+        }
+        if let Some(max_age) = self.max_age {
+            response_headers.insert(ACCESS_CONTROL_MAX_AGE, HeaderValue::from(max_age));
+        }
+    }
+}
+
+/// The methods a path accepts, stored in the request extensions once the handler is resolved.
+#[derive(Debug, Clone)]
+pub(crate) struct AllowedMethods(pub(crate) String);
+
+/// The handler path whose CORS policy applies to a request which matched no handler, like a
+/// preflight to a path registered for other methods.
+#[derive(Debug, Clone)]
+pub(crate) struct CorsScope(pub(crate) Vec<String>);
+
+#[cfg(test)]
+mod tests {
+    use actix_http::header::{HeaderMap, HeaderName, HeaderValue, ACCESS_CONTROL_ALLOW_CREDENTIALS, ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_EXPOSE_HEADERS, ACCESS_CONTROL_REQUEST_HEADERS, ACCESS_CONTROL_REQUEST_METHOD, ORIGIN, VARY};
+    use actix_http::Method as HttpMethod;
+    use regex::Regex;
+    use super::Cors;
+
+    fn headers(pairs: &[(HeaderName, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(name.clone(), HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    #[test]
+    fn any_origin_is_a_wildcard_without_credentials() {
+        let mut response = HeaderMap::new();
+        Cors::new().apply(&HttpMethod::GET, &headers(&[(ORIGIN, "https://a.com")]), &mut response, None);
+        assert_eq!(response.get(ACCESS_CONTROL_ALLOW_ORIGIN).unwrap(), "*");
+        assert!(response.get(VARY).is_none());
+    }
+
+    #[test]
+    fn listed_origin_is_echoed() {
+        let mut cors = Cors::allow_origins(vec!["https://a.com".to_owned()]);
+        cors.allow_credentials = true;
+        cors.exposed_headers = vec!["x-request-id".to_owned()];
+        let mut response = HeaderMap::new();
+        cors.apply(&HttpMethod::GET, &headers(&[(ORIGIN, "https://a.com")]), &mut response, None);
+        assert_eq!(response.get(ACCESS_CONTROL_ALLOW_ORIGIN).unwrap(), "https://a.com");
+        assert_eq!(response.get(ACCESS_CONTROL_ALLOW_CREDENTIALS).unwrap(), "true");
+        assert_eq!(response.get(ACCESS_CONTROL_EXPOSE_HEADERS).unwrap(), "x-request-id");
+        assert_eq!(response.get(VARY).unwrap(), "Origin");
+    }
+
+    #[test]
+    fn unlisted_origin_gets_no_cors_headers() {
+        let mut response = HeaderMap::new();
+        Cors::allow_origins(vec!["https://a.com".to_owned()]).apply(&HttpMethod::GET, &headers(&[(ORIGIN, "https://b.com")]), &mut response, None);
+        assert!(response.get(ACCESS_CONTROL_ALLOW_ORIGIN).is_none());
+        assert_eq!(response.get(VARY).unwrap(), "Origin");
+        let mut response = HeaderMap::new();
+        Cors::allow_origins(vec!["https://a.com".to_owned()]).apply(&HttpMethod::GET, &HeaderMap::new(), &mut response, None);
+        assert_eq!(response.get(VARY).unwrap(), "Origin");
+    }
+
+    #[test]
+    fn credentials_are_refused_for_any_origin() {
+        let mut cors = Cors::new();
+        cors.allow_credentials = true;
+        assert!(cors.validate().is_err());
+        let mut response = HeaderMap::new();
+        cors.apply(&HttpMethod::GET, &headers(&[(ORIGIN, "https://evil.com")]), &mut response, None);
+        assert_eq!(response.get(ACCESS_CONTROL_ALLOW_ORIGIN).unwrap(), "*");
+        assert!(response.get(ACCESS_CONTROL_ALLOW_CREDENTIALS).is_none());
+        let mut cors = Cors::allow_origins(vec!["https://a.com".to_owned()]);
+        cors.allow_credentials = true;
+        assert!(cors.validate().is_ok());
+    }
+
+    #[test]
+    fn regex_origin() {
+        let cors = Cors::allow_origin_regex(Regex::new(r"^https://[a-z]+\.a\.com$").unwrap());
+        let mut response = HeaderMap::new();
+        cors.apply(&HttpMethod::GET, &headers(&[(ORIGIN, "https://app.a.com")]), &mut response, None);
+        assert_eq!(response.get(ACCESS_CONTROL_ALLOW_ORIGIN).unwrap(), "https://app.a.com");
+    }
+
+    #[test]
+    fn origin_checks() {
+        let cors = Cors::allow_origins(vec!["https://a.com".to_owned()]);
+        assert!(cors.allows_origin(Some("https://a.com")));
+        assert!(!cors.allows_origin(Some("https://b.com")));
+        assert!(cors.allows_origin(None));
+        assert!(Cors::new().allows_origin(Some("https://b.com")));
+    }
+
+    #[test]
+    fn preflight_advertises_methods_and_headers() {
+        let request = headers(&[
+            (ORIGIN, "https://a.com"),
+            (ACCESS_CONTROL_REQUEST_METHOD, "POST"),
+            (ACCESS_CONTROL_REQUEST_HEADERS, "content-type"),
+        ]);
+        let mut response = HeaderMap::new();
+        Cors::new().apply(&HttpMethod::OPTIONS, &request, &mut response, Some("POST, OPTIONS"));
+        assert_eq!(response.get(ACCESS_CONTROL_ALLOW_METHODS).unwrap(), "POST, OPTIONS");
+        assert_eq!(response.get(ACCESS_CONTROL_ALLOW_HEADERS).unwrap(), "content-type");
+        assert!(response.get(ACCESS_CONTROL_EXPOSE_HEADERS).is_none());
+    }
+}
@@ -0,0 +1,383 @@
+use std::io::Write;
+use std::str::FromStr;
+use bigdecimal::BigDecimal;
+use bigdecimal::num_bigint::BigInt;
+use bson::oid::ObjectId;
+use chrono::{DateTime, NaiveDate, TimeZone, Utc};
+use ciborium::value::Value as CborValue;
+use rmpv::Value as MsgpackValue;
+use serde_json::{Value as JsonValue};
+use teo_result::{Error, Result};
+use teo_runtime::error_runtime_ext::ErrorRuntimeExt;
+use teo_teon::Value;
+
+const MSGPACK_EXT_TIMESTAMP: i8 = -1;
+const MSGPACK_EXT_DATE: i8 = 1;
+const MSGPACK_EXT_DECIMAL: i8 = 2;
+const MSGPACK_EXT_OBJECT_ID: i8 = 3;
+
+const CBOR_TAG_DATE_TIME: u64 = 0;
+const CBOR_TAG_EPOCH_DATE_TIME: u64 = 1;
+const CBOR_TAG_DECIMAL_FRACTION: u64 = 4;
+const CBOR_TAG_FULL_DATE: u64 = 1004;
+
+/// The encodings a request or response body can be in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum BodyEncoding {
+    Json,
+    MessagePack,
+    Cbor,
+}
+
+impl BodyEncoding {
+
+    pub(crate) fn from_content_type(content_type: &str) -> Option<Self> {
+        match content_type {
+            "" | "application/json" => Some(BodyEncoding::Json),
+            "application/msgpack" | "application/x-msgpack" | "application/vnd.msgpack" => Some(BodyEncoding::MessagePack),
+            "application/cbor" => Some(BodyEncoding::Cbor),
+            _ => None,
+        }
+    }
+
+    /// The supported encoding with the highest quality in an `Accept` header, JSON by default.
+    /// Encodings with `q=0` are refused. This applies to data responses, error responses are
+    /// always JSON.
+    pub(crate) fn from_accept(accept: Option<&str>) -> Self {
+        let mut result = BodyEncoding::Json;
+        let mut result_quality = 0.0f32;
+        for item in accept.unwrap_or("").split(',') {
+            let mut parts = item.split(';');
+            let mime = parts.next().unwrap_or("").trim();
+            let quality = parts
+                .filter_map(|p| p.trim().strip_prefix("q="))
+                .next()
+                .and_then(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            if let Some(encoding) = BodyEncoding::from_content_type(mime) {
+                if mime != "" && quality > result_quality {
+                    result = encoding;
+                    result_quality = quality;
+                }
+            }
+        }
+        result
+    }
+
+    pub(crate) fn mime(&self) -> &'static str {
+        match self {
+            BodyEncoding::Json => "application/json",
+            BodyEncoding::MessagePack => "application/msgpack",
+            BodyEncoding::Cbor => "application/cbor",
+        }
+    }
+
+    /// Decode a request body for handler input, which is validated as JSON. Dates, decimals
+    /// and object IDs become the strings the input validation accepts for these types.
+    pub(crate) fn decode(&self, body: &[u8]) -> Result<JsonValue> {
+        match self {
+            BodyEncoding::Json => serde_json::from_slice(body).map_err(|_| Error::value_error_message_only("incorrect json format")),
+            _ => Ok(serde_json::Value::try_from(&self.decode_teon(body)?)?),
+        }
+    }
+
+    pub(crate) fn decode_teon(&self, body: &[u8]) -> Result<Value> {
+        match self {
+            BodyEncoding::Json => self.decode(body).map(json_to_teon),
+            BodyEncoding::MessagePack => {
+                let mut reader = body;
+                let value = rmpv::decode::read_value(&mut reader).map_err(|_| Error::value_error_message_only("incorrect msgpack format"))?;
+                if !reader.is_empty() {
+                    Err(Error::value_error_message_only("incorrect msgpack format"))?
+                }
+                msgpack_to_teon(value)
+            }
+            BodyEncoding::Cbor => {
+                let value: CborValue = ciborium::de::from_reader(body).map_err(|_| Error::value_error_message_only("incorrect cbor format"))?;
+                cbor_to_teon(value)
+            }
+        }
+    }
+
+    pub(crate) fn encode(&self, value: &Value) -> Result<Vec<u8>> {
+        match self {
+            BodyEncoding::Json => {
+                let json_value = serde_json::Value::try_from(value)?;
+                serde_json::to_vec(&json_value).map_err(|e| Error::new(format!("{}", e)))
+            }
+            BodyEncoding::MessagePack => {
+                let mut buffer = vec![];
+                write_msgpack(&mut buffer, value)?;
+                Ok(buffer)
+            }
+            BodyEncoding::Cbor => {
+                let mut buffer = vec![];
+                ciborium::ser::into_writer(&cbor_value(value)?, &mut buffer).map_err(|e| Error::new(format!("{}", e)))?;
+                Ok(buffer)
+            }
+        }
+    }
+}
+
+fn write_msgpack(buffer: &mut Vec<u8>, value: &Value) -> Result<()> {
+    match value {
+        Value::Null => rmp::encode::write_nil(buffer).unwrap(),
+        Value::Bool(b) => rmp::encode::write_bool(buffer, *b).unwrap(),
+        Value::Int(i) => { rmp::encode::write_sint(buffer, *i as i64).unwrap(); },
+        Value::Int64(i) => { rmp::encode::write_sint(buffer, *i).unwrap(); },
+        Value::Float32(f) => rmp::encode::write_f32(buffer, *f).unwrap(),
+        Value::Float(f) => rmp::encode::write_f64(buffer, *f).unwrap(),
+        Value::String(s) => rmp::encode::write_str(buffer, s).unwrap(),
+        Value::Decimal(d) => write_msgpack_ext(buffer, MSGPACK_EXT_DECIMAL, d.to_string().as_bytes()),
+        Value::ObjectId(o) => write_msgpack_ext(buffer, MSGPACK_EXT_OBJECT_ID, &o.bytes()),
+        Value::Date(d) => write_msgpack_ext(buffer, MSGPACK_EXT_DATE, d.format("%Y-%m-%d").to_string().as_bytes()),
+        Value::DateTime(d) => {
+            // the 96-bit timestamp extension from the msgpack spec
+            let mut data = Vec::with_capacity(12);
+            data.extend_from_slice(&d.timestamp_subsec_nanos().to_be_bytes());
+            data.extend_from_slice(&d.timestamp().to_be_bytes());
+            write_msgpack_ext(buffer, MSGPACK_EXT_TIMESTAMP, &data)
+        }
+        Value::Array(a) => {
+            rmp::encode::write_array_len(buffer, a.len() as u32).unwrap();
+            for v in a {
+                write_msgpack(buffer, v)?;
+            }
+        }
+        Value::Dictionary(d) => {
+            rmp::encode::write_map_len(buffer, d.len() as u32).unwrap();
+            for (k, v) in d {
+                rmp::encode::write_str(buffer, k).unwrap();
+                write_msgpack(buffer, v)?;
+            }
+        }
+        _ => {
+            let json_value = serde_json::Value::try_from(value)?;
+            rmp_serde::encode::write(buffer, &json_value).map_err(|e| Error::new(format!("{}", e)))?;
+        }
+    }
+    Ok(())
+}
+
+fn write_msgpack_ext(buffer: &mut Vec<u8>, type_id: i8, data: &[u8]) {
+    rmp::encode::write_ext_meta(buffer, data.len() as u32, type_id).unwrap();
+    buffer.write_all(data).unwrap();
+}
+
+fn cbor_value(value: &Value) -> Result<CborValue> {
+    Ok(match value {
+        Value::Null => CborValue::Null,
+        Value::Bool(b) => CborValue::Bool(*b),
+        Value::Int(i) => CborValue::Integer((*i).into()),
+        Value::Int64(i) => CborValue::Integer((*i).into()),
+        Value::Float32(f) => CborValue::Float(*f as f64),
+        Value::Float(f) => CborValue::Float(*f),
+        Value::String(s) => CborValue::Text(s.clone()),
+        Value::Decimal(d) => {
+            let (mantissa, scale) = d.as_bigint_and_exponent();
+            match i64::try_from(&mantissa) {
+                Ok(mantissa) => CborValue::Tag(CBOR_TAG_DECIMAL_FRACTION, Box::new(CborValue::Array(vec![
+                    CborValue::Integer((-scale).into()),
+                    CborValue::Integer(mantissa.into()),
+                ]))),
+                // mantissas beyond 64 bits are sent as their string representation
+                Err(_) => CborValue::Text(d.to_string()),
+            }
+        }
+        Value::ObjectId(o) => CborValue::Bytes(o.bytes().to_vec()),
+        Value::Date(d) => CborValue::Tag(CBOR_TAG_FULL_DATE, Box::new(CborValue::Text(d.format("%Y-%m-%d").to_string()))),
+        Value::DateTime(d) => CborValue::Tag(CBOR_TAG_DATE_TIME, Box::new(CborValue::Text(d.to_rfc3339()))),
+        Value::Array(a) => CborValue::Array(a.iter().map(cbor_value).collect::<Result<Vec<CborValue>>>()?),
+        Value::Dictionary(d) => CborValue::Map(d.iter().map(|(k, v)| Ok((CborValue::Text(k.clone()), cbor_value(v)?))).collect::<Result<Vec<(CborValue, CborValue)>>>()?),
+        _ => {
+            let json_value = serde_json::Value::try_from(value)?;
+            CborValue::serialized(&json_value).map_err(|e| Error::new(format!("{}", e)))?
+        }
+    })
+}
+
+fn msgpack_to_teon(value: MsgpackValue) -> Result<Value> {
+    Ok(match value {
+        MsgpackValue::Nil => Value::Null,
+        MsgpackValue::Boolean(b) => Value::Bool(b),
+        MsgpackValue::Integer(i) => match i.as_i64() {
+            Some(i) => Value::Int64(i),
+            None => Value::Float(i.as_f64().unwrap_or(0.0)),
+        },
+        MsgpackValue::F32(f) => Value::Float32(f),
+        MsgpackValue::F64(f) => Value::Float(f),
+        MsgpackValue::String(s) => Value::String(s.into_str().ok_or_else(|| Error::value_error_message_only("msgpack string is not valid UTF-8"))?),
+        MsgpackValue::Binary(_) => Err(Error::value_error_message_only("msgpack binary values are not supported"))?,
+        MsgpackValue::Array(a) => Value::Array(a.into_iter().map(msgpack_to_teon).collect::<Result<Vec<Value>>>()?),
+        MsgpackValue::Map(m) => Value::Dictionary(m.into_iter().map(|(k, v)| match k {
+            MsgpackValue::String(k) => Ok((k.into_str().ok_or_else(|| Error::value_error_message_only("msgpack string is not valid UTF-8"))?, msgpack_to_teon(v)?)),
+            _ => Err(Error::value_error_message_only("msgpack map keys must be strings")),
+        }).collect::<Result<_>>()?),
+        MsgpackValue::Ext(type_id, data) => match type_id {
+            MSGPACK_EXT_TIMESTAMP => Value::DateTime(msgpack_timestamp(&data)?),
+            MSGPACK_EXT_DATE => Value::Date(parse_date(std::str::from_utf8(&data).unwrap_or(""))?),
+            MSGPACK_EXT_DECIMAL => Value::Decimal(parse_decimal(std::str::from_utf8(&data).unwrap_or(""))?),
+            MSGPACK_EXT_OBJECT_ID => Value::ObjectId(object_id(&data)?),
+            _ => Err(Error::value_error_message_only(format!("unknown msgpack extension type {}", type_id)))?,
+        },
+    })
+}
+
+/// The 32, 64 and 96-bit timestamp formats from the msgpack spec.
+fn msgpack_timestamp(data: &[u8]) -> Result<DateTime<Utc>> {
+    let (seconds, nanos) = match data.len() {
+        4 => (u32::from_be_bytes(data.try_into().unwrap()) as i64, 0),
+        8 => {
+            let value = u64::from_be_bytes(data.try_into().unwrap());
+            ((value & 0x3_ffff_ffff) as i64, (value >> 34) as u32)
+        }
+        12 => (i64::from_be_bytes(data[4..12].try_into().unwrap()), u32::from_be_bytes(data[0..4].try_into().unwrap())),
+        _ => Err(Error::value_error_message_only("invalid msgpack timestamp"))?,
+    };
+    Utc.timestamp_opt(seconds, nanos).single().ok_or_else(|| Error::value_error_message_only("invalid msgpack timestamp"))
+}
+
+fn cbor_to_teon(value: CborValue) -> Result<Value> {
+    Ok(match value {
+        CborValue::Null => Value::Null,
+        CborValue::Bool(b) => Value::Bool(b),
+        CborValue::Integer(i) => match i64::try_from(i) {
+            Ok(i) => Value::Int64(i),
+            Err(_) => Value::Float(i128::from(i) as f64),
+        },
+        CborValue::Float(f) => Value::Float(f),
+        CborValue::Text(s) => Value::String(s),
+        // object IDs are encoded as their 12 bytes
+        CborValue::Bytes(bytes) => Value::ObjectId(object_id(&bytes)?),
+        CborValue::Array(a) => Value::Array(a.into_iter().map(cbor_to_teon).collect::<Result<Vec<Value>>>()?),
+        CborValue::Map(m) => Value::Dictionary(m.into_iter().map(|(k, v)| match k {
+            CborValue::Text(k) => Ok((k, cbor_to_teon(v)?)),
+            _ => Err(Error::value_error_message_only("cbor map keys must be strings")),
+        }).collect::<Result<_>>()?),
+        CborValue::Tag(CBOR_TAG_DATE_TIME, inner) => match *inner {
+            CborValue::Text(s) => Value::DateTime(DateTime::parse_from_rfc3339(&s).map_err(|_| Error::value_error_message_only("invalid cbor date time"))?.with_timezone(&Utc)),
+            _ => Err(Error::value_error_message_only("invalid cbor date time"))?,
+        },
+        CborValue::Tag(CBOR_TAG_EPOCH_DATE_TIME, inner) => {
+            let seconds = match *inner {
+                CborValue::Integer(i) => i64::try_from(i).map(|i| i as f64).map_err(|_| Error::value_error_message_only("invalid cbor date time"))?,
+                CborValue::Float(f) => f,
+                _ => Err(Error::value_error_message_only("invalid cbor date time"))?,
+            };
+            Value::DateTime(Utc.timestamp_opt(seconds.floor() as i64, (seconds.fract() * 1e9) as u32).single().ok_or_else(|| Error::value_error_message_only("invalid cbor date time"))?)
+        }
+        CborValue::Tag(CBOR_TAG_DECIMAL_FRACTION, inner) => match *inner {
+            CborValue::Array(parts) => match parts.as_slice() {
+                [CborValue::Integer(exponent), CborValue::Integer(mantissa)] => {
+                    let exponent = i64::try_from(*exponent).map_err(|_| Error::value_error_message_only("invalid cbor decimal"))?;
+                    Value::Decimal(BigDecimal::new(BigInt::from(i128::from(*mantissa)), -exponent))
+                }
+                _ => Err(Error::value_error_message_only("invalid cbor decimal"))?,
+            },
+            _ => Err(Error::value_error_message_only("invalid cbor decimal"))?,
+        },
+        CborValue::Tag(CBOR_TAG_FULL_DATE, inner) => match *inner {
+            CborValue::Text(s) => Value::Date(parse_date(&s)?),
+            _ => Err(Error::value_error_message_only("invalid cbor date"))?,
+        },
+        CborValue::Tag(_, inner) => cbor_to_teon(*inner)?,
+        _ => Err(Error::value_error_message_only("unsupported cbor value"))?,
+    })
+}
+
+fn parse_date(s: &str) -> Result<NaiveDate> {
+    NaiveDate::parse_from_str(s, "%Y-%m-%d").map_err(|_| Error::value_error_message_only(format!("invalid date `{}`", s)))
+}
+
+fn parse_decimal(s: &str) -> Result<BigDecimal> {
+    BigDecimal::from_str(s).map_err(|_| Error::value_error_message_only(format!("invalid decimal `{}`", s)))
+}
+
+fn object_id(bytes: &[u8]) -> Result<ObjectId> {
+    let bytes: [u8; 12] = bytes.try_into().map_err(|_| Error::value_error_message_only("invalid object id"))?;
+    Ok(ObjectId::from_bytes(bytes))
+}
+
+pub(crate) fn json_to_teon(json_value: JsonValue) -> Value {
+    match json_value {
+        JsonValue::Null => Value::Null,
+        JsonValue::Bool(b) => Value::Bool(b),
+        JsonValue::Number(n) => if let Some(i) = n.as_i64() {
+            Value::Int64(i)
+        } else {
+            Value::Float(n.as_f64().unwrap_or(0.0))
+        },
+        JsonValue::String(s) => Value::String(s),
+        JsonValue::Array(a) => Value::Array(a.into_iter().map(json_to_teon).collect()),
+        JsonValue::Object(o) => Value::Dictionary(o.into_iter().map(|(k, v)| (k, json_to_teon(v))).collect()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+    use bigdecimal::BigDecimal;
+    use bson::oid::ObjectId;
+    use chrono::{NaiveDate, TimeZone, Utc};
+    use serde_json::json;
+    use teo_teon::Value;
+    use super::BodyEncoding;
+
+    fn values() -> Vec<Value> {
+        vec![
+            Value::Date(NaiveDate::from_ymd_opt(2024, 2, 29).unwrap()),
+            Value::DateTime(Utc.timestamp_opt(1_700_000_000, 123_000_000).unwrap()),
+            Value::Decimal(BigDecimal::from_str("-12.345").unwrap()),
+            Value::ObjectId(ObjectId::from_bytes([1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12])),
+            Value::String("a".to_owned()),
+            Value::Int64(-5),
+            Value::Bool(true),
+            Value::Null,
+        ]
+    }
+
+    #[test]
+    fn msgpack_round_trip() {
+        for value in values() {
+            let encoded = BodyEncoding::MessagePack.encode(&value).unwrap();
+            assert_eq!(BodyEncoding::MessagePack.decode_teon(&encoded).unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn cbor_round_trip() {
+        for value in values() {
+            let encoded = BodyEncoding::Cbor.encode(&value).unwrap();
+            assert_eq!(BodyEncoding::Cbor.decode_teon(&encoded).unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn nested_values_round_trip() {
+        let value = Value::Dictionary(vec![
+            ("items".to_owned(), Value::Array(values())),
+        ].into_iter().collect());
+        for encoding in [BodyEncoding::MessagePack, BodyEncoding::Cbor] {
+            let encoded = encoding.encode(&value).unwrap();
+            assert_eq!(encoding.decode_teon(&encoded).unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn extension_values_decode_into_input_json() {
+        let value = Value::Dictionary(vec![
+            ("date".to_owned(), Value::Date(NaiveDate::from_ymd_opt(2024, 2, 29).unwrap())),
+        ].into_iter().collect());
+        let encoded = BodyEncoding::MessagePack.encode(&value).unwrap();
+        assert_eq!(BodyEncoding::MessagePack.decode(&encoded).unwrap(), json!({ "date": "2024-02-29" }));
+    }
+
+    #[test]
+    fn accept_negotiation() {
+        assert_eq!(BodyEncoding::from_accept(None), BodyEncoding::Json);
+        assert_eq!(BodyEncoding::from_accept(Some("application/cbor")), BodyEncoding::Cbor);
+        assert_eq!(BodyEncoding::from_accept(Some("application/json;q=0.5, application/msgpack")), BodyEncoding::MessagePack);
+        assert_eq!(BodyEncoding::from_accept(Some("application/msgpack;q=0")), BodyEncoding::Json);
+        assert_eq!(BodyEncoding::from_accept(Some("application/msgpack;q=0, application/cbor;q=0.1")), BodyEncoding::Cbor);
+    }
+}
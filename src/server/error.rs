@@ -5,6 +5,7 @@ use actix_web::{HttpResponse, ResponseError};
 use serde_json::json;
 use teo_teon::Value;
 use teo_result::Error;
+use crate::server::request_id::current_request_id;
 
 #[derive(Debug)]
 pub(super) struct WrapError(Error);
@@ -30,10 +31,40 @@ impl ResponseError for WrapError {
     }
 
     fn error_response(&self) -> HttpResponse<BoxBody> {
-        let value: Value = (&self.0).into();
-        let json_value: serde_json::Value = value.try_into().unwrap();
-        HttpResponse::Ok().status(self.status_code()).json(json!({
-            "error": json_value
-        }))
+        HttpResponse::Ok().status(self.status_code()).json(error_body(&self.0))
+    }
+}
+
+/// An error which is answered with the HTTP status `code`.
+pub(crate) fn status_error(code: u16, message: impl Into<String>) -> Error {
+    let mut error = Error::new(message.into());
+    error.code = Some(code);
+    error
+}
+
+/// The JSON body of an error response, with the ID of the current request if there's one.
+pub(super) fn error_body(error: &Error) -> serde_json::Value {
+    let value: Value = error.into();
+    let json_value: serde_json::Value = value.try_into().unwrap();
+    let mut body = json!({
+        "error": json_value
+    });
+    if let Some(request_id) = current_request_id() {
+        body["requestId"] = json!(request_id);
+    }
+    body
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_http::StatusCode;
+    use actix_web::ResponseError;
+    use super::{status_error, WrapError};
+
+    #[test]
+    fn status_error_sets_the_response_status() {
+        let error = status_error(413, "payload too large");
+        assert_eq!(error.code, Some(413));
+        assert_eq!(WrapError::from(error).status_code(), StatusCode::PAYLOAD_TOO_LARGE);
     }
 }
@@ -0,0 +1,138 @@
+use serde_json::{json, Map, Value as JsonValue};
+use teo_result::{Error, Result};
+use teo_runtime::error_runtime_ext::ErrorRuntimeExt;
+
+enum Segment {
+    Key(String),
+    Index(usize),
+    Push,
+}
+
+/// Insert a form value by its field name. Bracket paths like `tags[]`, `address[city]` and
+/// `items[0][name]` build nested arrays and objects.
+pub(crate) fn insert_form_value(result: &mut Map<String, JsonValue>, name: &str, value: JsonValue) -> Result<()> {
+    let (head, segments) = match parse_field_name(name) {
+        Some(parsed) => parsed,
+        None => {
+            result.insert(name.to_owned(), value);
+            return Ok(())
+        }
+    };
+    let slot = result.entry(head).or_insert(JsonValue::Null);
+    assign(slot, &segments, value, name)
+}
+
+fn parse_field_name(name: &str) -> Option<(String, Vec<Segment>)> {
+    let bracket = name.find('[')?;
+    if bracket == 0 || !name.ends_with(']') {
+        return None;
+    }
+    let head = name[0..bracket].to_owned();
+    let mut segments = vec![];
+    let mut rest = &name[bracket..];
+    while !rest.is_empty() {
+        let inner = rest.strip_prefix('[')?;
+        let end = inner.find(']')?;
+        let key = &inner[0..end];
+        segments.push(if key.is_empty() {
+            Segment::Push
+        } else if let Ok(index) = key.parse::<usize>() {
+            Segment::Index(index)
+        } else {
+            Segment::Key(key.to_owned())
+        });
+        rest = &inner[end + 1..];
+    }
+    Some((head, segments))
+}
+
+fn assign(slot: &mut JsonValue, segments: &[Segment], value: JsonValue, name: &str) -> Result<()> {
+    let segment = match segments.first() {
+        Some(segment) => segment,
+        None => {
+            *slot = value;
+            return Ok(())
+        }
+    };
+    match segment {
+        Segment::Key(key) => {
+            if slot.is_null() {
+                *slot = json!({});
+            }
+            let object = slot.as_object_mut().ok_or_else(|| conflict_error(name))?;
+            let child = object.entry(key.clone()).or_insert(JsonValue::Null);
+            assign(child, &segments[1..], value, name)
+        }
+        Segment::Index(index) => {
+            if slot.is_null() {
+                *slot = json!([]);
+            }
+            let array = slot.as_array_mut().ok_or_else(|| conflict_error(name))?;
+            // indices must be sequential, this prevents huge allocations from `a[99999999]`
+            if *index > array.len() {
+                Err(Error::value_error_message_only(format!("form field `{}` skips array indices", name)))?
+            }
+            if *index == array.len() {
+                array.push(JsonValue::Null);
+            }
+            assign(&mut array[*index], &segments[1..], value, name)
+        }
+        Segment::Push => {
+            if slot.is_null() {
+                *slot = json!([]);
+            }
+            let array = slot.as_array_mut().ok_or_else(|| conflict_error(name))?;
+            // `items[][name]` followed by `items[][price]` describe the same element
+            let reuse_last = match (segments.get(1), array.last()) {
+                (Some(Segment::Key(key)), Some(JsonValue::Object(last))) => !last.contains_key(key),
+                _ => false,
+            };
+            if !reuse_last {
+                array.push(JsonValue::Null);
+            }
+            let last = array.last_mut().unwrap();
+            assign(last, &segments[1..], value, name)
+        }
+    }
+}
+
+fn conflict_error(name: &str) -> Error {
+    Error::value_error_message_only(format!("form field `{}` conflicts with another field", name))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Map, Value as JsonValue};
+    use super::insert_form_value;
+
+    fn form(fields: &[(&str, &str)]) -> JsonValue {
+        let mut result = Map::new();
+        for (name, value) in fields {
+            insert_form_value(&mut result, name, json!(value)).unwrap();
+        }
+        JsonValue::Object(result)
+    }
+
+    #[test]
+    fn plain_names() {
+        assert_eq!(form(&[("name", "a"), ("age", "1")]), json!({ "name": "a", "age": "1" }));
+        assert_eq!(form(&[("[a]", "1")]), json!({ "[a]": "1" }));
+    }
+
+    #[test]
+    fn nested_objects_and_arrays() {
+        assert_eq!(form(&[("address[city]", "x"), ("address[zip]", "1")]), json!({ "address": { "city": "x", "zip": "1" } }));
+        assert_eq!(form(&[("tags[]", "a"), ("tags[]", "b")]), json!({ "tags": ["a", "b"] }));
+        assert_eq!(form(&[("items[0][name]", "a"), ("items[1][name]", "b")]), json!({ "items": [{ "name": "a" }, { "name": "b" }] }));
+        assert_eq!(form(&[("items[][name]", "a"), ("items[][price]", "1"), ("items[][name]", "b")]), json!({ "items": [{ "name": "a", "price": "1" }, { "name": "b" }] }));
+    }
+
+    #[test]
+    fn skipped_indices_and_conflicts_are_rejected() {
+        let mut result = Map::new();
+        assert!(insert_form_value(&mut result, "a[5]", json!("x")).is_err());
+        let mut result = Map::new();
+        insert_form_value(&mut result, "a", json!("x")).unwrap();
+        assert!(insert_form_value(&mut result, "a[b]", json!("y")).is_err());
+    }
+}
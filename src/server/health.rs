@@ -0,0 +1,128 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use actix_http::{HttpMessage, Method as HttpMethod};
+use actix_web::{HttpRequest, HttpResponse};
+use indexmap::IndexMap;
+use serde_json::{json, Map, Value as JsonValue};
+use teo_result::Result;
+use teo_runtime::connection::connection::Connection;
+use teo_runtime::database::database::Database;
+use teo_teon::Value;
+use crate::app::Ctx;
+
+static READY: AtomicBool = AtomicBool::new(false);
+
+/// Liveness and readiness probes for orchestrators. Set a path to `None` to disable the
+/// endpoint. Probe requests are not written to the access log.
+#[derive(Debug, Clone)]
+pub struct Health {
+    pub liveness_path: Option<String>,
+    pub readiness_path: Option<String>,
+    /// How long each database may take to answer the query sent by the readiness probe.
+    pub timeout: Duration,
+}
+
+impl Default for Health {
+
+    fn default() -> Self {
+        Self {
+            liveness_path: Some("/healthz".to_owned()),
+            readiness_path: Some("/readyz".to_owned()),
+            timeout: Duration::from_secs(2),
+        }
+    }
+}
+
+impl Health {
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+/// Marks a probe request in the request extensions.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct HealthCheck;
+
+/// The server is ready once migration, autoseed and setup are done, and until it's shutting down.
+pub(crate) fn set_ready(ready: bool) {
+    READY.store(ready, Ordering::SeqCst);
+}
+
+pub(crate) async fn serve_health(health: &Health, http_request: &HttpRequest) -> Option<HttpResponse> {
+    if http_request.method() != HttpMethod::GET && http_request.method() != HttpMethod::HEAD {
+        return None;
+    }
+    let path = Some(http_request.path());
+    if path == health.liveness_path.as_deref() {
+        http_request.extensions_mut().insert(HealthCheck);
+        Some(HttpResponse::Ok().json(json!({ "status": "ok" })))
+    } else if path == health.readiness_path.as_deref() {
+        http_request.extensions_mut().insert(HealthCheck);
+        Some(readiness(health.timeout).await)
+    } else {
+        None
+    }
+}
+
+async fn readiness(timeout: Duration) -> HttpResponse {
+    if !READY.load(Ordering::SeqCst) {
+        return HttpResponse::ServiceUnavailable().json(json!({ "status": "starting" }));
+    }
+    let mut ready = true;
+    let mut namespaces = Map::new();
+    let conn_ctx = Ctx::conn_ctx();
+    for (namespace_path, connection) in conn_ctx.connections_iter() {
+        let name = if namespace_path.is_empty() { "main".to_owned() } else { namespace_path.join(".") };
+        let database = conn_ctx.namespace().namespace_at_path(&namespace_path.iter().map(AsRef::as_ref).collect()).and_then(|n| n.database);
+        let status = match tokio::time::timeout(timeout, ping(connection.as_ref(), database)).await {
+            Ok(Ok(_)) => "ok".to_owned(),
+            Ok(Err(err)) => format!("error: {}", err),
+            Err(_) => "timeout".to_owned(),
+        };
+        ready = ready && status == "ok";
+        namespaces.insert(name, JsonValue::String(status));
+    }
+    let body = json!({ "status": if ready { "ok" } else { "unavailable" }, "namespaces": namespaces });
+    if ready {
+        HttpResponse::Ok().json(body)
+    } else {
+        HttpResponse::ServiceUnavailable().json(body)
+    }
+}
+
+/// Send the cheapest query the database answers, so a dropped connection or a stalled server
+/// fails the probe instead of only the pool handing out a connection.
+async fn ping(connection: &dyn Connection, database: Option<Database>) -> Result<()> {
+    let transaction = connection.no_transaction().await?;
+    transaction.query_raw(&ping_query(database)).await?;
+    Ok(())
+}
+
+fn ping_query(database: Option<Database>) -> Value {
+    match database {
+        Some(Database::MongoDB) => {
+            let mut command = IndexMap::new();
+            command.insert("ping".to_owned(), Value::Int(1));
+            Value::Dictionary(command)
+        }
+        _ => Value::String("SELECT 1".to_owned()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use indexmap::IndexMap;
+    use teo_runtime::database::database::Database;
+    use teo_teon::Value;
+    use super::ping_query;
+
+    #[test]
+    fn probes_send_a_query() {
+        assert_eq!(ping_query(Some(Database::SQLite)), Value::String("SELECT 1".to_owned()));
+        assert_eq!(ping_query(Some(Database::PostgreSQL)), Value::String("SELECT 1".to_owned()));
+        let mut command = IndexMap::new();
+        command.insert("ping".to_owned(), Value::Int(1));
+        assert_eq!(ping_query(Some(Database::MongoDB)), Value::Dictionary(command));
+    }
+}
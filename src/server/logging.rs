@@ -0,0 +1,61 @@
+use std::path::PathBuf;
+use teo_result::{Error, Result};
+use crate::server::request_id::set_current_identity;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Colored lines for development.
+    Pretty,
+    /// One JSON object per line.
+    Json,
+}
+
+impl LogFormat {
+
+    pub(crate) fn from_name(name: &str) -> Result<Self> {
+        match name {
+            "pretty" => Ok(LogFormat::Pretty),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(Error::new(format!("unknown log format `{}`, expect `pretty` or `json`", name))),
+        }
+    }
+}
+
+/// Where and how server messages and access logs are written. The `--log-format` and
+/// `--log-file` flags of `serve` override these.
+#[derive(Debug, Clone)]
+pub struct Logging {
+    pub format: LogFormat,
+    /// Write to this file instead of stdout.
+    pub file: Option<PathBuf>,
+    /// The file is rotated when it would grow beyond this many bytes.
+    pub max_file_size: u64,
+    /// How many rotated files are kept, as `file.1` to `file.N`.
+    pub max_files: usize,
+}
+
+impl Default for Logging {
+
+    fn default() -> Self {
+        Self {
+            format: LogFormat::Pretty,
+            file: None,
+            max_file_size: 10 * 1024 * 1024,
+            max_files: 5,
+        }
+    }
+}
+
+impl Logging {
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+/// Record who is making the current request, it's included in the access log line. Call it
+/// from a middleware once the identity is known. The server doesn't read identities from
+/// sessions or tokens itself, the field is empty unless this is called.
+pub fn set_log_identity(identity: impl Into<String>) {
+    set_current_identity(identity.into());
+}
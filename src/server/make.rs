@@ -1,26 +1,26 @@
 use std::sync::Arc;
-use std::time::SystemTime;
+use std::time::{Instant, SystemTime};
 use actix_web::dev::Service;
 use futures_util::FutureExt;
 use colored::Colorize;
 use futures_util::future;
+use tracing::{info_span, Instrument};
 use serde_json::{Value as JsonValue};
 use teo_result::{Error, Result};
 use teo_runtime::config::server::Server;
 use teo_runtime::namespace::Namespace;
 use actix_http::body::MessageBody;
 use actix_http::{HttpMessage, Method as HttpMethod};
-use actix_web::{App, FromRequest, HttpRequest, HttpResponse, HttpServer, web};
+use actix_http::encoding::Encoder;
+use actix_http::header::{HeaderName, HeaderValue, ACCEPT_ENCODING, ALLOW, CONTENT_LENGTH, ORIGIN};
+use actix_http::body::BodySize;
+use actix_web::{App, FromRequest, HttpRequest, HttpResponse, HttpServer, ResponseError, web};
 use actix_web::dev::{ServiceFactory, ServiceRequest, ServiceResponse};
-use actix_web::middleware::DefaultHeaders;
 use teo_parser::ast::handler::HandlerInputFormat;
-use teo_runtime::action::Action;
-use teo_runtime::handler::action::builtin_action_handler_from_name;
 use teo_runtime::handler::Handler;
 use teo_runtime::handler::handler::Method;
 use teo_runtime::{connection, request};
 use teo_runtime::connection::transaction;
-use teo_runtime::handler::default::{create, find_first, find_many, find_unique, update, upsert, copy, create_many, update_many, copy_many, delete_many, count, aggregate, group_by, delete};
 use teo_runtime::model::Model;
 use teo_runtime::response::Response;
 use teo_teon::Value;
@@ -29,21 +29,39 @@ use crate::cli::runtime_version::RuntimeVersion;
 use crate::purge;
 use crate::seeder::seed::seed;
 use crate::server::parse::{parse_form_body, parse_json_body};
-use teo_runtime::handler::input::{validate_and_transform_json_input_for_handler, validate_and_transform_json_input_for_builtin_action};
+use crate::server::query::parse_query_string;
+use teo_runtime::handler::input::validate_and_transform_json_input_for_handler;
 use teo_runtime::handler::r#match::HandlerMatch;
 use teo_runtime::schema::load::load_data_sets::load_data_sets;
 use crate::app::Ctx;
 use crate::app::database::connect_databases;
 use crate::cli::command::SeedCommandAction;
 use crate::message::{info_message, request_message, unhandled_request_message};
-use crate::server::error::WrapError;
+use crate::server::actions::{ModelAction, ModelActionRegistry};
+use crate::server::batch::handle_batch;
+use crate::server::cors::{AllowedMethods, CorsScope};
+use crate::server::error::{status_error, WrapError};
+use crate::server::health::{serve_health, HealthCheck};
+use crate::server::metrics::{metrics_response, record_request, HandlerGuard, ResponseSize};
+use crate::server::options::ServerOptions;
+use crate::server::rate_limit::{limit_request, IdentityLimit};
 use crate::server::request::RequestImpl;
-use crate::server::responder::IntoHttpResponse;
+use crate::server::request_id::{assign_request_id, current_identity, with_request_id, RequestId, REQUEST_ID_HEADER};
+use crate::server::responder::{respond, IntoHttpResponse};
+use crate::server::shutdown::{shutdown_on_signal, shutdown_timeout_secs};
+use crate::server::static_files::{serve_spa_fallback, serve_static_mounts};
+use crate::server::subscription::{hold_changes, publish_changes};
+use crate::server::telemetry::{record_handler, request_span};
+use crate::server::timeout::with_timeout;
+use crate::server::tls::redirect_to_https;
+use crate::server::upload::keep_stored_files;
+use crate::server::websocket::{is_websocket_upgrade, upgrade, WEBSOCKET_ACCEPTED_HEADER};
 use teo_runtime::error_runtime_ext::ErrorRuntimeExt;
 
 fn make_server_app(
     main_namespace: &'static Namespace,
     conf: &'static Server,
+    options: &'static ServerOptions,
 ) -> App<impl ServiceFactory<
     ServiceRequest,
     Response = ServiceResponse<impl MessageBody>,
@@ -52,41 +70,111 @@ fn make_server_app(
     Error = actix_web::Error,
 > + 'static> {
     let app = App::new()
-        .wrap(DefaultHeaders::new()
-            .add(("Access-Control-Allow-Origin", "*"))
-            .add(("Access-Control-Allow-Methods", "OPTIONS, POST, GET"))
-            .add(("Access-Control-Allow-Headers", "*"))
-            .add(("Access-Control-Max-Age", "86400")))
-        .wrap_fn(|req, srv| {
+        .app_data(options)
+        .wrap_fn(move |req, srv| {
+            let accept_encoding = req.headers().get(ACCEPT_ENCODING).and_then(|a| a.to_str().ok()).map(|a| a.to_owned());
+            let fut = srv.call(req);
+            async move {
+                let mut res = fut.await?;
+                let size = res.response().body().size();
+                if let BodySize::Sized(size) = size {
+                    res.request().extensions_mut().insert(ResponseSize(size));
+                }
+                let status = res.status();
+                let encoding = options.compression.encoding_for(accept_encoding.as_deref(), status, res.headers_mut(), size);
+                Ok(res.map_body(move |head, body| Encoder::response(encoding, head, body)))
+            }
+        })
+        .wrap_fn(move |req, srv| {
+            let fut = srv.call(req);
+            async move {
+                let mut res = fut.await?;
+                let http_request = res.request().clone();
+                let extensions = http_request.extensions();
+                let handler_path = extensions.get::<HandlerMatch>().map(|m| &m.path).or_else(|| extensions.get::<CorsScope>().map(|s| &s.0));
+                let cors = options.cors_for(handler_path);
+                let allowed_methods = extensions.get::<AllowedMethods>().map(|m| m.0.as_str());
+                cors.apply(http_request.method(), http_request.headers(), res.headers_mut(), allowed_methods);
+                Ok(res)
+            }
+        })
+        .wrap_fn(move |req, srv| {
             let start = SystemTime::now();
+            let request_size = req.headers().get(CONTENT_LENGTH).and_then(|l| l.to_str().ok()).and_then(|l| l.parse::<u64>().ok());
             let fut = srv.call(req);
             async move {
                 let res = fut.await?;
-                {
+                if res.request().extensions().get::<HealthCheck>().is_none() {
                     let binding = res.request().extensions();
                     let handler_found_info = binding.get::<HandlerMatch>().clone();
                     let time_elapsed = SystemTime::now().duration_since(start).unwrap();
                     let path = res.request().path();
-                    let method = res.request().method().as_str();
+                    let method = res.request().method();
+                    let request_id = binding.get::<RequestId>().map(|id| id.0.as_str());
+                    let identity = current_identity();
                     if let Some(handler_found_info) = handler_found_info {
-                        request_message(time_elapsed, method, path, &handler_found_info.path, handler_found_info.name.as_str(), res.response().status().as_u16());
+                        request_message(time_elapsed, method.as_str(), path, &handler_found_info.path, handler_found_info.name.as_str(), res.response().status().as_u16(), request_id, identity.as_deref());
                     } else {
-                        unhandled_request_message(time_elapsed, method, path, res.response().status().as_u16());
+                        unhandled_request_message(time_elapsed, method.as_str(), path, res.response().status().as_u16(), request_id, identity.as_deref());
+                    }
+                    if options.metrics.enabled {
+                        // the compression middleware records the size before encoding the body
+                        let response_size = binding.get::<ResponseSize>().map(|s| s.0);
+                        record_request(handler_found_info.map(|m| handler_path(m).join(".")), method, res.response().status().as_u16(), time_elapsed, request_size, response_size);
                     }
                 }
                 Ok(res)
             }
         })
+        .wrap_fn(|mut req, srv| {
+            let request_id = assign_request_id(req.headers_mut());
+            req.extensions_mut().insert(RequestId(request_id.clone()));
+            let span = request_span(req.headers(), req.method().as_str(), req.path(), &request_id);
+            let fut = span.in_scope(|| srv.call(req));
+            let status_span = span.clone();
+            with_request_id(request_id.clone(), async move {
+                let mut res = fut.await?;
+                status_span.record("http.status_code", res.status().as_u16());
+                if let Ok(value) = HeaderValue::from_str(&request_id) {
+                    res.headers_mut().insert(HeaderName::from_static(REQUEST_ID_HEADER), value);
+                }
+                Ok(res)
+            }.instrument(span))
+        })
         .default_service(web::route().to(move |http_request: HttpRequest, payload: web::Payload| async move {
+            let started = Instant::now();
+            if options.metrics.is_metrics_request(&http_request) {
+                return Ok::<HttpResponse, WrapError>(metrics_response(&options.metrics, &http_request, main_namespace));
+            }
+            if let Some(response) = serve_health(&options.health, &http_request).await {
+                return Ok::<HttpResponse, WrapError>(response);
+            }
+            if let Some(response) = serve_static_mounts(&options.static_mounts, &http_request) {
+                return Ok::<HttpResponse, WrapError>(response);
+            }
             // validate path
             let path = main_namespace.handler_map.remove_path_prefix(http_request.path(), conf.path_prefix.as_ref().map(|s| s.as_str()));
-            let method = method_from(http_request.method())?;
+            if options.batch_path.as_deref() == Some(path) {
+                if http_request.method() != HttpMethod::POST {
+                    return Ok::<HttpResponse, WrapError>(allow_response(&http_request, "POST, OPTIONS"));
+                }
+                let _metrics_guard = HandlerGuard::new(&options.metrics, "batch".to_owned(), &main_namespace.path);
+                let json_body = with_timeout(options, &vec!["batch".to_owned()], started, None, parse_json_body(&http_request, payload, &options.body_limits)).await?;
+                return Ok::<HttpResponse, WrapError>(handle_batch(main_namespace, options, &http_request, json_body, started).await);
+            }
+            let method = match method_from(http_request.method()) {
+                Some(method) => method,
+                None => return Ok::<HttpResponse, WrapError>(method_not_allowed(&http_request, main_namespace, &options.model_actions, path)),
+            };
             let match_result = if let Some(m_result) = main_namespace.handler_map.r#match(method, path) {
                 m_result
             } else if let Some(m_result) = main_namespace.handler_map.default_match(method, path) {
                 m_result
+            } else if let Some(response) = serve_spa_fallback(&options.static_mounts, &http_request) {
+                return Ok::<HttpResponse, WrapError>(response);
             } else {
-                Err(Error::not_found_message_only())?
+                // the path exists for other methods, or doesn't exist at all
+                return Ok::<HttpResponse, WrapError>(method_not_allowed(&http_request, main_namespace, &options.model_actions, path));
             };
 
             // High-risk operations for testing
@@ -100,6 +188,8 @@ fn make_server_app(
             }
 
             // Normal handling
+            let resolve_span = info_span!("resolve");
+            let resolve_guard = resolve_span.enter();
             let mut group = false;
             let dest_namespace = if let Some(d) = main_namespace.namespace_at_path(&match_result.path()) {
                 d
@@ -119,14 +209,14 @@ fn make_server_app(
                         if let Some(handler) = group.handlers.get(match_result.handler_name()) {
                             (dest_namespace, HandlerResolved::Custom(handler))
                         } else {
-                            if let Some(action) = builtin_action_handler_from_name(match_result.handler_name()) {
+                            if let Some(action) = options.model_actions.get(&match_result.path, match_result.handler_name()) {
                                 (dest_namespace, HandlerResolved::Builtin(model, action))
                             } else {
                                 Err(Error::not_found_message_only())?
                             }
                         }
                     } else {
-                        if let Some(action) = builtin_action_handler_from_name(match_result.handler_name()) {
+                        if let Some(action) = options.model_actions.get(&match_result.path, match_result.handler_name()) {
                             (dest_namespace, HandlerResolved::Builtin(model, action))
                         } else {
                             Err(Error::not_found_message_only())?
@@ -150,6 +240,48 @@ fn make_server_app(
             };
             let dest_namespace = handler_resolved.0;
             let handler_resolved = handler_resolved.1;
+            drop(resolve_guard);
+            record_handler(&handler_path(&match_result).join("."));
+            http_request.extensions_mut().insert(match_result.clone());
+            let _metrics_guard = HandlerGuard::new(&options.metrics, handler_path(&match_result).join("."), &dest_namespace.path);
+            let allow = allowed_methods(main_namespace, &options.model_actions, path).unwrap_or_else(|| "OPTIONS".to_owned());
+            http_request.extensions_mut().insert(AllowedMethods(allow.clone()));
+            let mut permit = if method != Method::Options {
+                match limit_request(options, &handler_path(&match_result), &http_request).await {
+                    Ok(permit) => permit,
+                    Err(response) => return Ok::<HttpResponse, WrapError>(response),
+                }
+            } else {
+                None
+            };
+            if method == Method::Get && is_websocket_upgrade(&http_request) {
+                if let Some(websocket_handler) = options.websocket_handlers.get(&handler_path(&match_result).join(".")) {
+                    // browsers don't apply CORS to WebSocket handshakes, the origin is checked here
+                    let origin = http_request.headers().get(ORIGIN).and_then(|o| o.to_str().ok());
+                    if !options.cors_for(Some(&match_result.path)).allows_origin(origin) {
+                        return Ok::<HttpResponse, WrapError>(WrapError::from(status_error(403, "origin is not allowed")).error_response());
+                    }
+                    let conn_ctx = connection::Ctx::from_namespace(main_namespace);
+                    let transaction_ctx = transaction::Ctx::new(conn_ctx.clone());
+                    let request = request::Request::new(Arc::new(RequestImpl::new(http_request.clone())));
+                    let ctx = request::Ctx::new(
+                        request.clone(),
+                        Arc::new(Value::Null),
+                        transaction_ctx,
+                        match_result.clone(),
+                    );
+                    // the handshake goes through the namespace middlewares like other requests
+                    let response = dest_namespace.middleware_stack.call(ctx, &|ctx: request::Ctx| async {
+                        let response = Response::empty();
+                        response.headers().set(WEBSOCKET_ACCEPTED_HEADER, "true");
+                        Ok(response)
+                    }).await?;
+                    if response.headers().get(WEBSOCKET_ACCEPTED_HEADER).is_none() {
+                        return Ok::<HttpResponse, WrapError>(respond(response, http_request.clone()).await);
+                    }
+                    return Ok::<HttpResponse, WrapError>(upgrade(&http_request, payload, websocket_handler.clone(), request, match_result.clone(), conn_ctx, options.websocket_ping_interval, permit.take())?);
+                }
+            }
             if method == Method::Options {
                 // special handle for options
                 let conn_ctx = connection::Ctx::from_namespace(main_namespace);
@@ -160,98 +292,90 @@ fn make_server_app(
                     transaction_ctx,
                     match_result
                 );
-                return Ok::<HttpResponse, WrapError>(dest_namespace.middleware_stack.call(ctx, &|ctx: request::Ctx| async {
+                let response = dest_namespace.middleware_stack.call(ctx, &|ctx: request::Ctx| async {
                     Ok(Response::empty())
-                }).await?.into_http_response(http_request.clone()));
+                }).await?;
+                response.headers().set("allow", allow);
+                return Ok::<HttpResponse, WrapError>(respond(response, http_request.clone()).await);
             }
-            http_request.extensions_mut().insert(match_result.clone());
             // parse body
             let mut format = HandlerInputFormat::Json;
+            let mut input_type = None;
             match handler_resolved {
                 HandlerResolved::Custom(handler) => {
                     format = handler.format;
+                    input_type = Some(&handler.input_type);
                 }
                 _ => (),
             }
-            let json_body = match format {
-                HandlerInputFormat::Json => if method == Method::Get || method == Method::Delete {
-                    JsonValue::Null
-                } else {
-                    parse_json_body(payload).await?
-                },
-                HandlerInputFormat::Form => parse_form_body(http_request.clone(), payload).await?,
-            };
+            let body_limits = options.body_limits_for(&handler_path(&match_result));
+            let json_body = with_timeout(options, &handler_path(&match_result), started, None, async {
+                Ok::<JsonValue, Error>(match format {
+                    HandlerInputFormat::Json => if method == Method::Get || method == Method::Delete {
+                        parse_query_string(http_request.query_string(), input_type, main_namespace)?
+                    } else {
+                        parse_json_body(&http_request, payload, body_limits).await?
+                    },
+                    HandlerInputFormat::Form => parse_form_body(http_request.clone(), payload, body_limits, options).await?,
+                })
+            }.instrument(info_span!("parse_body"))).await?;
             return match handler_resolved {
                 HandlerResolved::Builtin(model, action) => {
-                    let body = validate_and_transform_json_input_for_builtin_action(model, action, &json_body, main_namespace)?;
+                    let body = info_span!("validate_input").in_scope(|| action.transform_input(model, &json_body, main_namespace))?;
                     let conn_ctx = connection::Ctx::from_namespace(main_namespace);
                     let transaction_ctx = transaction::Ctx::new(conn_ctx);
                     let ctx = request::Ctx::new(
                         request::Request::new(Arc::new(RequestImpl::new(http_request.clone()))),
                         Arc::new(body),
-                        transaction_ctx,
+                        transaction_ctx.clone(),
                         match_result.clone(),
                     );
-                    match match_result.handler_name() {
-                        "findMany" => Ok::<HttpResponse, WrapError>(dest_namespace.middleware_stack.call(ctx, &|ctx: request::Ctx| async move {
-                            find_many(&ctx).await
-                        }).await?.into_http_response(http_request.clone())),
-                        "findFirst" => Ok::<HttpResponse, WrapError>(dest_namespace.middleware_stack.call(ctx, &|ctx: request::Ctx| async move {
-                            find_first(&ctx).await
-                        }).await?.into_http_response(http_request.clone())),
-                        "findUnique" => Ok::<HttpResponse, WrapError>(dest_namespace.middleware_stack.call(ctx, &|ctx: request::Ctx| async move {
-                            find_unique(&ctx).await
-                        }).await?.into_http_response(http_request.clone())),
-                        "create" => Ok::<HttpResponse, WrapError>(dest_namespace.middleware_stack.call(ctx, &|ctx: request::Ctx| async move {
-                            create(&ctx).await
-                        }).await?.into_http_response(http_request.clone())),
-                        "delete" => Ok::<HttpResponse, WrapError>(dest_namespace.middleware_stack.call(ctx, &|ctx: request::Ctx| async move {
-                            delete(&ctx).await
-                        }).await?.into_http_response(http_request.clone())),
-                        "update" => Ok::<HttpResponse, WrapError>(dest_namespace.middleware_stack.call(ctx, &|ctx: request::Ctx| async move {
-                            update(&ctx).await
-                        }).await?.into_http_response(http_request.clone())),
-                        "upsert" => Ok::<HttpResponse, WrapError>(dest_namespace.middleware_stack.call(ctx, &|ctx: request::Ctx| async move {
-                            upsert(&ctx).await
-                        }).await?.into_http_response(http_request.clone())),
-                        "copy" => Ok::<HttpResponse, WrapError>(dest_namespace.middleware_stack.call(ctx, &|ctx: request::Ctx| async move {
-                            copy(&ctx).await
-                        }).await?.into_http_response(http_request.clone())),
-                        "createMany" => Ok::<HttpResponse, WrapError>(dest_namespace.middleware_stack.call(ctx, &|ctx: request::Ctx| async move {
-                            create_many(&ctx).await
-                        }).await?.into_http_response(http_request.clone())),
-                        "updateMany" => Ok::<HttpResponse, WrapError>(dest_namespace.middleware_stack.call(ctx, &|ctx: request::Ctx| async move {
-                            update_many(&ctx).await
-                        }).await?.into_http_response(http_request.clone())),
-                        "copyMany" => Ok::<HttpResponse, WrapError>(dest_namespace.middleware_stack.call(ctx, &|ctx: request::Ctx| async move {
-                            copy_many(&ctx).await
-                        }).await?.into_http_response(http_request.clone())),
-                        "deleteMany" => Ok::<HttpResponse, WrapError>(dest_namespace.middleware_stack.call(ctx, &|ctx: request::Ctx| async move {
-                            delete_many(&ctx).await
-                        }).await?.into_http_response(http_request.clone())),
-                        "count" => Ok::<HttpResponse, WrapError>(dest_namespace.middleware_stack.call(ctx, &|ctx: request::Ctx| async move {
-                            count(&ctx).await
-                        }).await?.into_http_response(http_request.clone())),
-                        "aggregate" => Ok::<HttpResponse, WrapError>(dest_namespace.middleware_stack.call(ctx, &|ctx: request::Ctx| async move {
-                            aggregate(&ctx).await
-                        }).await?.into_http_response(http_request.clone())),
-                        "groupBy" => Ok::<HttpResponse, WrapError>(dest_namespace.middleware_stack.call(ctx, &|ctx: request::Ctx| async move {
-                            group_by(&ctx).await
-                        }).await?.into_http_response(http_request.clone())),
-                        _ => Err(Error::not_found_message_only())?,
+                    let identity_limit = IdentityLimit::new(options, &handler_path(&match_result), &http_request);
+                    let (result, changes) = hold_changes(with_timeout(options, &handler_path(&match_result), started, Some(&transaction_ctx), action.call(dest_namespace, ctx, identity_limit.clone()))).await;
+                    if let Some(response) = identity_limit.and_then(|limit| limit.rejected_response()) {
+                        return Ok::<HttpResponse, WrapError>(response);
+                    }
+                    let response = result?;
+                    if response.code() < 400 {
+                        keep_stored_files(&http_request);
+                        publish_changes(changes);
                     }
+                    Ok::<HttpResponse, WrapError>(respond(response, http_request.clone()).await)
                 },
                 HandlerResolved::Custom(handler) => {
-                    let body = validate_and_transform_json_input_for_handler(handler, &json_body, main_namespace)?;
+                    let body = info_span!("validate_input").in_scope(|| validate_and_transform_json_input_for_handler(handler, &json_body, main_namespace))?;
+                    let handler_path = handler_path(&match_result);
                     let conn_ctx = connection::Ctx::from_namespace(main_namespace);
                     let transaction_ctx = transaction::Ctx::new(conn_ctx);
                     let ctx = request::Ctx::new(
                         request::Request::new(Arc::new(RequestImpl::new(http_request.clone()))),
                         Arc::new(body),
-                        transaction_ctx,
+                        transaction_ctx.clone(),
                         match_result
                     );
-                    Ok::<HttpResponse, WrapError>(dest_namespace.middleware_stack.call(ctx, handler.call).await?.into_http_response(http_request.clone()))
+                    let handler_call = handler.call;
+                    let identity_limit = IdentityLimit::new(options, &handler_path, &http_request);
+                    let handler_identity_limit = identity_limit.clone();
+                    // created outside of the middlewares span so that it's not its child
+                    let handler_span = info_span!("handler");
+                    let (result, changes) = hold_changes(with_timeout(options, &handler_path, started, Some(&transaction_ctx), dest_namespace.middleware_stack.call(ctx, &move |ctx: request::Ctx| {
+                        let identity_limit = handler_identity_limit.clone();
+                        async move {
+                            if let Some(identity_limit) = identity_limit {
+                                identity_limit.check().await?;
+                            }
+                            handler_call.call(ctx).await
+                        }.instrument(handler_span.clone())
+                    }).instrument(info_span!("middlewares")))).await;
+                    if let Some(response) = identity_limit.and_then(|limit| limit.rejected_response()) {
+                        return Ok::<HttpResponse, WrapError>(response);
+                    }
+                    let response = result?;
+                    if response.code() < 400 {
+                        keep_stored_files(&http_request);
+                        publish_changes(changes);
+                    }
+                    Ok::<HttpResponse, WrapError>(respond(response, http_request.clone()).await)
                 }
             }
         }));
@@ -261,23 +385,54 @@ fn make_server_app(
 pub(crate) async fn serve(
     namespace: &'static Namespace,
     conf: &'static Server,
+    options: &'static ServerOptions,
     runtime_version: &'static RuntimeVersion,
     entrance: &'static Entrance,
     silent: bool,
 ) -> Result<()> {
+    options.validate()?;
     let bind = conf.bind.clone();
     let port = bind.1;
     let server = HttpServer::new(move || {
-        make_server_app(namespace, conf)
+        make_server_app(namespace, conf, options)
     })
-        .bind((bind.0, bind.1 as u16))
-        .unwrap()
-        .run();
-    let result = future::join(server, server_start_message(port as u16, runtime_version, entrance, silent)).await;
+        .disable_signals()
+        .shutdown_timeout(shutdown_timeout_secs(options.shutdown_timeout));
+    let server = if let Some(tls) = &options.tls {
+        server.bind_rustls_0_22((bind.0.clone(), bind.1 as u16), tls.server_config()?)
+    } else {
+        server.bind((bind.0.clone(), bind.1 as u16))
+    }.map_err(|e| Error::new(format!("cannot bind to port {}: {}", port, e)))?.run();
+    let mut handles = vec![server.handle()];
+    let redirect_server = if let Some((tls, redirect_port)) = options.tls.as_ref().and_then(|tls| Some((tls, tls.redirect_http_port?))) {
+        let redirect_host = tls.redirect_host(&bind.0)?;
+        let redirect_server = HttpServer::new(move || {
+            App::new().default_service(redirect_to_https(redirect_host.clone(), port as u16))
+        })
+            .disable_signals()
+            .bind((bind.0.clone(), redirect_port))
+            .map_err(|e| Error::new(format!("cannot bind to port {}: {}", redirect_port, e)))?
+            .run();
+        handles.push(redirect_server.handle());
+        Some(redirect_server)
+    } else {
+        None
+    };
+    tokio::spawn(shutdown_on_signal(handles, silent));
+    let servers = future::join(server, async move {
+        match redirect_server {
+            Some(redirect_server) => redirect_server.await,
+            None => Ok(()),
+        }
+    });
+    let result = future::join(servers, server_start_message(port as u16, options.tls.is_some(), runtime_version, entrance, silent)).await;
+    if let Err(err) = result.0.0.and(result.0.1) {
+        Err(Error::new(format!("{}", err)))?
+    }
     result.1
 }
 
-async fn server_start_message(port: u16, runtime_version: &'static RuntimeVersion, entrance: &'static Entrance, silent: bool) -> Result<()> {
+async fn server_start_message(port: u16, tls: bool, runtime_version: &'static RuntimeVersion, entrance: &'static Entrance, silent: bool) -> Result<()> {
     if silent { return Ok(()) }
     // Introducing
     let teo_version = env!("CARGO_PKG_VERSION");
@@ -285,22 +440,104 @@ async fn server_start_message(port: u16, runtime_version: &'static RuntimeVersio
     info_message(format!("{} ({}, {})", teo, runtime_version.to_string(), entrance.to_str()));
     // Listening
     let port_str = format!("{port}").bold();
-    info_message(format!("listening on port {}", port_str));
+    if tls {
+        info_message(format!("listening on port {} with TLS", port_str));
+    } else {
+        info_message(format!("listening on port {}", port_str));
+    }
     Ok(())
 }
 
-fn method_from(m: &HttpMethod) -> Result<Method> {
-    Ok(match m.as_str() {
-        "GET" => Method::Get,
+/// The handler method for an HTTP method. `HEAD` requests are served by `GET` handlers, the
+/// body is dropped when the response is written.
+fn method_from(m: &HttpMethod) -> Option<Method> {
+    Some(match m.as_str() {
+        "GET" | "HEAD" => Method::Get,
         "POST" => Method::Post,
         "PATCH" => Method::Patch,
         "PUT" => Method::Put,
         "DELETE" => Method::Delete,
         "OPTIONS" => Method::Options,
-        _ => Err(Error::new(format!("unknown http method {}", m.as_str())))?
+        _ => return None,
+    })
+}
+
+/// The methods registered for `path` in the handler map, formatted for the `Allow` header.
+fn allowed_methods(main_namespace: &Namespace, actions: &ModelActionRegistry, path: &str) -> Option<String> {
+    let mut methods = vec![];
+    for method in [Method::Get, Method::Post, Method::Patch, Method::Put, Method::Delete] {
+        if match_for(main_namespace, actions, method, path).is_some() {
+            methods.push(method_name(method));
+            if method == Method::Get {
+                methods.push("HEAD");
+            }
+        }
+    }
+    if methods.is_empty() {
+        return None;
+    }
+    methods.push("OPTIONS");
+    Some(methods.join(", "))
+}
+
+/// The handler of `path` for `method`. Model actions which are disabled for the model don't
+/// count.
+fn match_for(main_namespace: &Namespace, actions: &ModelActionRegistry, method: Method, path: &str) -> Option<HandlerMatch> {
+    main_namespace.handler_map.r#match(method, path).or_else(|| {
+        main_namespace.handler_map.default_match(method, path).filter(|m| actions.get(&m.path, m.handler_name()).is_some())
     })
 }
 
+/// A handler registered for `path` with any method.
+fn any_match(main_namespace: &Namespace, actions: &ModelActionRegistry, path: &str) -> Option<HandlerMatch> {
+    [Method::Get, Method::Post, Method::Patch, Method::Put, Method::Delete].into_iter().find_map(|method| match_for(main_namespace, actions, method, path))
+}
+
+/// 405 with `Allow` for a path which exists for other methods, 404 otherwise. Preflight
+/// requests to such a path receive 204.
+fn method_not_allowed(http_request: &HttpRequest, main_namespace: &Namespace, actions: &ModelActionRegistry, path: &str) -> HttpResponse {
+    let allow = match allowed_methods(main_namespace, actions, path) {
+        Some(allow) => allow,
+        None => return WrapError::from(Error::not_found_message_only()).error_response(),
+    };
+    if let Some(handler_match) = any_match(main_namespace, actions, path) {
+        // no handler is matched, the namespace CORS policy still applies to preflights
+        http_request.extensions_mut().insert(CorsScope(handler_match.path));
+    }
+    allow_response(http_request, &allow)
+}
+
+/// Answer `OPTIONS` with 204 and other methods with 405, listing the methods of the path.
+fn allow_response(http_request: &HttpRequest, allow: &str) -> HttpResponse {
+    http_request.extensions_mut().insert(AllowedMethods(allow.to_owned()));
+    let mut response = if http_request.method() == HttpMethod::OPTIONS {
+        HttpResponse::NoContent().finish()
+    } else {
+        WrapError::from(status_error(405, format!("method {} is not allowed", http_request.method()))).error_response()
+    };
+    if let Ok(allow) = HeaderValue::from_str(allow) {
+        response.headers_mut().insert(ALLOW, allow);
+    }
+    response
+}
+
+fn handler_path(handler_match: &HandlerMatch) -> Vec<String> {
+    let mut path = handler_match.path.clone();
+    path.push(handler_match.name.clone());
+    path
+}
+
+fn method_name(m: Method) -> &'static str {
+    match m {
+        Method::Get => "GET",
+        Method::Post => "POST",
+        Method::Patch => "PATCH",
+        Method::Put => "PUT",
+        Method::Delete => "DELETE",
+        Method::Options => "OPTIONS",
+    }
+}
+
 async fn dangerous_operation(action :&str)-> Result<Response>{
         let dangerous_operation = DangerousOperations::try_from(action)?;
         match dangerous_operation {
@@ -329,7 +566,7 @@ async fn dangerous_operation(action :&str)-> Result<Response>{
 
 enum HandlerResolved<'a> {
     Custom(&'a Handler),
-    Builtin(&'a Model, Action),
+    Builtin(&'a Model, &'a ModelAction),
 }
 
 #[derive(Debug)]
@@ -363,3 +600,30 @@ fn seed_from_dangerous_operation (danger_operation :DangerousOperations)->Result
         _ => Err(Error::new("cant create seedCommandAction from DangerousOperation")),
 }
 }
+
+#[cfg(test)]
+mod tests {
+    use actix_http::Method as HttpMethod;
+    use actix_http::StatusCode;
+    use actix_web::test::TestRequest;
+    use teo_runtime::handler::handler::Method;
+    use teo_runtime::namespace::Namespace;
+    use crate::server::actions::ModelActionRegistry;
+    use super::{allowed_methods, method_from, method_not_allowed};
+
+    #[test]
+    fn head_is_dispatched_as_get() {
+        assert!(method_from(&HttpMethod::HEAD) == Some(Method::Get));
+        assert!(method_from(&HttpMethod::OPTIONS) == Some(Method::Options));
+        assert!(method_from(&HttpMethod::TRACE).is_none());
+    }
+
+    #[test]
+    fn unknown_paths_are_not_found() {
+        let namespace = Namespace::main();
+        let actions = ModelActionRegistry::new();
+        assert!(allowed_methods(&namespace, &actions, "/missing").is_none());
+        let http_request = TestRequest::default().method(HttpMethod::PATCH).to_http_request();
+        assert_eq!(method_not_allowed(&http_request, &namespace, &actions, "/missing").status(), StatusCode::NOT_FOUND);
+    }
+}
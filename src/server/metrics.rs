@@ -0,0 +1,250 @@
+use std::net::IpAddr;
+use std::time::Duration;
+use actix_web::{HttpRequest, HttpResponse};
+use actix_web::http::Method;
+use actix_web::http::header::AUTHORIZATION;
+use once_cell::sync::Lazy;
+use prometheus::{Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGaugeVec, Opts, Registry, TextEncoder};
+use teo_runtime::namespace::Namespace;
+
+/// The Prometheus endpoint. It's disabled by default, set `enabled` to serve it. Besides the
+/// request metrics, `teo_transactions_total` counts the transactions of handlers by outcome,
+/// `committed` when the handler succeeded, `rolled_back` when it failed and `aborted` when it
+/// timed out. `teo_database_connections` is 1 for namespaces connected to their database, the
+/// connectors don't report the size of their pools.
+#[derive(Debug, Clone)]
+pub struct Metrics {
+    pub enabled: bool,
+    pub path: String,
+    /// Only these peers can scrape when it's set.
+    pub allowed_ips: Option<Vec<IpAddr>>,
+    /// Scrapers have to send `Authorization: Bearer <token>` when it's set. When neither this
+    /// nor `allowed_ips` is set, anyone who can reach the server can scrape.
+    pub bearer_token: Option<String>,
+}
+
+impl Default for Metrics {
+
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            path: "/metrics".to_owned(),
+            allowed_ips: None,
+            bearer_token: None,
+        }
+    }
+}
+
+impl Metrics {
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub(crate) fn is_metrics_request(&self, http_request: &HttpRequest) -> bool {
+        self.enabled && http_request.path() == self.path
+    }
+
+    fn allows(&self, http_request: &HttpRequest) -> bool {
+        if let Some(allowed_ips) = &self.allowed_ips {
+            match http_request.peer_addr() {
+                Some(peer) if allowed_ips.contains(&peer.ip()) => (),
+                _ => return false,
+            }
+        }
+        if let Some(token) = &self.bearer_token {
+            let authorization = http_request.headers().get(AUTHORIZATION).and_then(|a| a.to_str().ok());
+            if authorization.and_then(|a| a.strip_prefix("Bearer ")) != Some(token.as_str()) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+struct Collectors {
+    registry: Registry,
+    requests: IntCounterVec,
+    duration: HistogramVec,
+    in_flight: IntGaugeVec,
+    request_size: HistogramVec,
+    response_size: HistogramVec,
+    namespace_requests: IntCounterVec,
+    namespace_in_flight: IntGaugeVec,
+    connections: IntGaugeVec,
+    transactions: IntCounterVec,
+}
+
+static COLLECTORS: Lazy<Collectors> = Lazy::new(|| {
+    let registry = Registry::new();
+    let size_buckets = prometheus::exponential_buckets(64.0, 4.0, 10).unwrap();
+    let requests = IntCounterVec::new(Opts::new("teo_http_requests_total", "Requests by handler, method and status"), &["handler", "method", "status"]).unwrap();
+    let duration = HistogramVec::new(HistogramOpts::new("teo_http_request_duration_seconds", "Request latencies by handler"), &["handler"]).unwrap();
+    let in_flight = IntGaugeVec::new(Opts::new("teo_http_requests_in_flight", "Requests being handled by handler"), &["handler"]).unwrap();
+    let request_size = HistogramVec::new(HistogramOpts::new("teo_http_request_size_bytes", "Request body sizes by handler").buckets(size_buckets.clone()), &["handler"]).unwrap();
+    let response_size = HistogramVec::new(HistogramOpts::new("teo_http_response_size_bytes", "Response body sizes before compression by handler").buckets(size_buckets), &["handler"]).unwrap();
+    let namespace_requests = IntCounterVec::new(Opts::new("teo_namespace_requests_total", "Requests dispatched to handlers by namespace"), &["namespace"]).unwrap();
+    let namespace_in_flight = IntGaugeVec::new(Opts::new("teo_namespace_requests_in_flight", "Requests being handled by namespace"), &["namespace"]).unwrap();
+    let connections = IntGaugeVec::new(Opts::new("teo_database_connections", "1 when the namespace is connected to its database"), &["namespace"]).unwrap();
+    let transactions = IntCounterVec::new(Opts::new("teo_transactions_total", "Transactions of handlers by outcome"), &["outcome"]).unwrap();
+    registry.register(Box::new(requests.clone())).unwrap();
+    registry.register(Box::new(duration.clone())).unwrap();
+    registry.register(Box::new(in_flight.clone())).unwrap();
+    registry.register(Box::new(request_size.clone())).unwrap();
+    registry.register(Box::new(response_size.clone())).unwrap();
+    registry.register(Box::new(namespace_requests.clone())).unwrap();
+    registry.register(Box::new(namespace_in_flight.clone())).unwrap();
+    registry.register(Box::new(connections.clone())).unwrap();
+    registry.register(Box::new(transactions.clone())).unwrap();
+    Collectors { registry, requests, duration, in_flight, request_size, response_size, namespace_requests, namespace_in_flight, connections, transactions }
+});
+
+/// Counts a request as in flight for its handler and the handler's namespace until it's dropped.
+pub(crate) struct HandlerGuard {
+    handler: String,
+    namespace: String,
+}
+
+impl HandlerGuard {
+
+    pub(crate) fn new(metrics: &Metrics, handler: String, namespace_path: &Vec<String>) -> Option<Self> {
+        if !metrics.enabled {
+            return None;
+        }
+        let namespace = namespace_label(namespace_path);
+        COLLECTORS.in_flight.with_label_values(&[&handler]).inc();
+        COLLECTORS.namespace_requests.with_label_values(&[&namespace]).inc();
+        COLLECTORS.namespace_in_flight.with_label_values(&[&namespace]).inc();
+        Some(Self { handler, namespace })
+    }
+}
+
+impl Drop for HandlerGuard {
+
+    fn drop(&mut self) {
+        COLLECTORS.in_flight.with_label_values(&[&self.handler]).dec();
+        COLLECTORS.namespace_in_flight.with_label_values(&[&self.namespace]).dec();
+    }
+}
+
+/// The size of a response body before it's compressed, stored in the request extensions.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ResponseSize(pub(crate) u64);
+
+/// Record a finished request. Requests which matched no handler are recorded as `unmatched`.
+pub(crate) fn record_request(handler: Option<String>, method: &Method, status: u16, elapsed: Duration, request_size: Option<u64>, response_size: Option<u64>) {
+    let handler = handler.unwrap_or_else(|| "unmatched".to_owned());
+    COLLECTORS.requests.with_label_values(&[&handler, method_label(method), &status.to_string()]).inc();
+    COLLECTORS.duration.with_label_values(&[&handler]).observe(elapsed.as_secs_f64());
+    if let Some(request_size) = request_size {
+        COLLECTORS.request_size.with_label_values(&[&handler]).observe(request_size as f64);
+    }
+    if let Some(response_size) = response_size {
+        COLLECTORS.response_size.with_label_values(&[&handler]).observe(response_size as f64);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum TransactionOutcome {
+    Committed,
+    RolledBack,
+    Aborted,
+}
+
+impl TransactionOutcome {
+
+    fn label(&self) -> &'static str {
+        match self {
+            TransactionOutcome::Committed => "committed",
+            TransactionOutcome::RolledBack => "rolled_back",
+            TransactionOutcome::Aborted => "aborted",
+        }
+    }
+}
+
+pub(crate) fn record_transaction(metrics: &Metrics, outcome: TransactionOutcome) {
+    if metrics.enabled {
+        COLLECTORS.transactions.with_label_values(&[outcome.label()]).inc();
+    }
+}
+
+/// Serve the metrics. The endpoint is open to everyone unless `allowed_ips` or `bearer_token`
+/// is set.
+pub(crate) fn metrics_response(metrics: &Metrics, http_request: &HttpRequest, main_namespace: &Namespace) -> HttpResponse {
+    if !metrics.allows(http_request) {
+        return HttpResponse::Forbidden().finish();
+    }
+    update_connections(main_namespace);
+    let encoder = TextEncoder::new();
+    let mut buffer = vec![];
+    if encoder.encode(&COLLECTORS.registry.gather(), &mut buffer).is_err() {
+        return HttpResponse::InternalServerError().finish();
+    }
+    HttpResponse::Ok().content_type(encoder.format_type()).body(buffer)
+}
+
+fn update_connections(namespace: &Namespace) {
+    COLLECTORS.connections.with_label_values(&[&namespace_label(&namespace.path)]).set(if namespace.connection.is_some() { 1 } else { 0 });
+    for namespace in namespace.namespaces.values() {
+        update_connections(namespace);
+    }
+}
+
+fn namespace_label(path: &Vec<String>) -> String {
+    if path.is_empty() { "main".to_owned() } else { path.join(".") }
+}
+
+/// Arbitrary method tokens are recorded as `OTHER` to bound the label cardinality.
+fn method_label(method: &Method) -> &'static str {
+    match *method {
+        Method::GET => "GET",
+        Method::HEAD => "HEAD",
+        Method::POST => "POST",
+        Method::PUT => "PUT",
+        Method::PATCH => "PATCH",
+        Method::DELETE => "DELETE",
+        Method::OPTIONS => "OPTIONS",
+        _ => "OTHER",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use actix_web::http::Method;
+    use super::{method_label, namespace_label, record_request, record_transaction, Metrics, TransactionOutcome, COLLECTORS};
+
+    #[test]
+    fn unknown_methods_share_a_label() {
+        assert_eq!(method_label(&Method::GET), "GET");
+        assert_eq!(method_label(&Method::DELETE), "DELETE");
+        assert_eq!(method_label(&Method::from_bytes(b"PURGE").unwrap()), "OTHER");
+        assert_eq!(method_label(&Method::from_bytes(b"X-RANDOM-1234").unwrap()), "OTHER");
+    }
+
+    #[test]
+    fn namespace_labels() {
+        assert_eq!(namespace_label(&vec![]), "main");
+        assert_eq!(namespace_label(&vec!["a".to_owned(), "b".to_owned()]), "a.b");
+    }
+
+    #[test]
+    fn requests_are_recorded_with_normalized_methods() {
+        let handler = "metrics_test.record".to_owned();
+        record_request(Some(handler.clone()), &Method::from_bytes(b"PURGE").unwrap(), 200, Duration::from_millis(5), None, Some(2048));
+        assert_eq!(COLLECTORS.requests.with_label_values(&[&handler, "OTHER", "200"]).get(), 1);
+        assert_eq!(COLLECTORS.response_size.with_label_values(&[&handler]).get_sample_count(), 1);
+        assert_eq!(COLLECTORS.response_size.with_label_values(&[&handler]).get_sample_sum(), 2048.0);
+    }
+
+    #[test]
+    fn transactions_are_counted_when_enabled() {
+        let mut metrics = Metrics::new();
+        let before = COLLECTORS.transactions.with_label_values(&["rolled_back"]).get();
+        record_transaction(&metrics, TransactionOutcome::RolledBack);
+        assert_eq!(COLLECTORS.transactions.with_label_values(&["rolled_back"]).get(), before);
+        metrics.enabled = true;
+        record_transaction(&metrics, TransactionOutcome::RolledBack);
+        assert_eq!(COLLECTORS.transactions.with_label_values(&["rolled_back"]).get(), before + 1);
+    }
+}
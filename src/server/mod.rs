@@ -4,3 +4,25 @@ pub mod request;
 pub mod responder;
 pub mod error;
 pub mod static_files;
+pub mod cors;
+pub mod options;
+pub mod tls;
+pub mod upload;
+pub mod storage;
+pub mod stream;
+pub mod websocket;
+pub mod subscription;
+pub mod compression;
+pub mod actions;
+pub mod request_id;
+pub mod logging;
+pub mod metrics;
+pub mod telemetry;
+pub mod health;
+pub mod rate_limit;
+mod shutdown;
+mod form;
+mod query;
+mod encoding;
+mod batch;
+mod timeout;
@@ -0,0 +1,204 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use educe::Educe;
+use teo_result::Result;
+use crate::server::actions::ModelActionRegistry;
+use crate::server::compression::Compression;
+use crate::server::cors::Cors;
+use crate::server::health::Health;
+use crate::server::logging::Logging;
+use crate::server::metrics::Metrics;
+use crate::server::rate_limit::{ConcurrencyLimit, MemoryRateLimitStore, RateLimit, RateLimitStore};
+use crate::server::static_files::StaticMount;
+use crate::server::storage::FileStorage;
+use crate::server::telemetry::Telemetry;
+use crate::server::tls::Tls;
+use crate::server::websocket::WebSocketHandler;
+
+/// Server behaviors which are configured from the app rather than from the schema's `server`
+/// block, whose fields are defined by the runtime. Some of them are overridden by flags of
+/// `serve`. Namespace specific settings are keyed by the dot joined namespace path.
+#[derive(Educe, Clone)]
+#[educe(Debug)]
+pub struct ServerOptions {
+    pub cors: Cors,
+    pub namespace_cors: BTreeMap<String, Cors>,
+    /// How long in-flight requests are given to finish after a shutdown signal is received.
+    pub shutdown_timeout: Duration,
+    /// Serve HTTPS instead of plain HTTP when set.
+    pub tls: Option<Tls>,
+    pub body_limits: BodyLimits,
+    /// Body limits for handlers or handler groups, keyed by the dot joined handler path.
+    pub handler_body_limits: BTreeMap<String, BodyLimits>,
+    /// Where multipart uploads are written when there's no file storage, defaults to
+    /// `teo-uploads` in the system temp directory.
+    pub upload_dir: Option<PathBuf>,
+    /// Where uploaded files are streamed to before handlers receive them. Without a storage,
+    /// uploads are written to `upload_dir` and removed after the request.
+    pub file_storage: Option<Arc<dyn FileStorage>>,
+    /// WebSocket endpoints, keyed by the dot joined path of the `GET` handler they serve.
+    #[educe(Debug(ignore))]
+    pub websocket_handlers: BTreeMap<String, Arc<dyn WebSocketHandler>>,
+    /// How often connected WebSocket clients are pinged. Clients silent for twice as long are
+    /// disconnected.
+    pub websocket_ping_interval: Duration,
+    pub compression: Compression,
+    pub static_mounts: Vec<StaticMount>,
+    /// The path of the batch endpoint, which runs several builtin actions in one transaction.
+    /// It's disabled when `None`.
+    pub batch_path: Option<String>,
+    /// Batches with more operations are rejected with 413.
+    pub batch_max_operations: usize,
+    /// The actions available on every model.
+    #[educe(Debug(ignore))]
+    pub model_actions: ModelActionRegistry,
+    pub logging: Logging,
+    pub metrics: Metrics,
+    pub telemetry: Telemetry,
+    pub health: Health,
+    /// Rate limits for handlers or handler groups, keyed by the dot joined handler path. Each
+    /// handler has its own buckets.
+    pub rate_limits: BTreeMap<String, RateLimit>,
+    pub rate_limit_store: Arc<dyn RateLimitStore>,
+    /// Concurrency limits for handlers or handler groups, keyed by the dot joined handler path.
+    /// The handlers of a group share the limit set for the group.
+    pub concurrency_limits: BTreeMap<String, ConcurrencyLimit>,
+    /// How long reading the body, the middlewares and the handler of a request may take before
+    /// it's cancelled with 504. There's no limit when `None`.
+    pub request_timeout: Option<Duration>,
+    /// Timeouts for handlers or handler groups, keyed by the dot joined handler path.
+    pub handler_timeouts: BTreeMap<String, Duration>,
+}
+
+impl Default for ServerOptions {
+
+    fn default() -> Self {
+        Self {
+            cors: Cors::default(),
+            namespace_cors: BTreeMap::new(),
+            shutdown_timeout: Duration::from_secs(30),
+            tls: None,
+            body_limits: BodyLimits::default(),
+            handler_body_limits: BTreeMap::new(),
+            upload_dir: None,
+            file_storage: None,
+            websocket_handlers: BTreeMap::new(),
+            websocket_ping_interval: Duration::from_secs(30),
+            compression: Compression::default(),
+            static_mounts: vec![],
+            batch_path: None,
+            batch_max_operations: 100,
+            model_actions: ModelActionRegistry::default(),
+            logging: Logging::default(),
+            metrics: Metrics::default(),
+            telemetry: Telemetry::default(),
+            health: Health::default(),
+            rate_limits: BTreeMap::new(),
+            rate_limit_store: Arc::new(MemoryRateLimitStore::new()),
+            concurrency_limits: BTreeMap::new(),
+            request_timeout: None,
+            handler_timeouts: BTreeMap::new(),
+        }
+    }
+}
+
+impl ServerOptions {
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set_namespace_cors(&mut self, namespace_path: &str, cors: Cors) {
+        self.namespace_cors.insert(namespace_path.to_owned(), cors);
+    }
+
+    pub fn set_handler_body_limits(&mut self, handler_path: &str, body_limits: BodyLimits) {
+        self.handler_body_limits.insert(handler_path.to_owned(), body_limits);
+    }
+
+    pub fn add_websocket_handler<H>(&mut self, handler_path: &str, handler: H) where H: WebSocketHandler + 'static {
+        self.websocket_handlers.insert(handler_path.to_owned(), Arc::new(handler));
+    }
+
+    pub fn add_static_mount(&mut self, mount: StaticMount) {
+        self.static_mounts.push(mount);
+    }
+
+    pub fn set_rate_limit(&mut self, handler_path: &str, rate_limit: RateLimit) {
+        self.rate_limits.insert(handler_path.to_owned(), rate_limit);
+    }
+
+    pub fn set_concurrency_limit(&mut self, handler_path: &str, concurrency_limit: ConcurrencyLimit) {
+        self.concurrency_limits.insert(handler_path.to_owned(), concurrency_limit);
+    }
+
+    pub fn set_handler_timeout(&mut self, handler_path: &str, timeout: Duration) {
+        self.handler_timeouts.insert(handler_path.to_owned(), timeout);
+    }
+
+    pub(crate) fn body_limits_for(&self, handler_path: &Vec<String>) -> &BodyLimits {
+        longest_prefix_match(&self.handler_body_limits, handler_path).unwrap_or(&self.body_limits)
+    }
+
+    pub(crate) fn upload_dir(&self) -> PathBuf {
+        self.upload_dir.clone().unwrap_or_else(|| std::env::temp_dir().join("teo-uploads"))
+    }
+
+    pub(crate) fn rate_limit_for(&self, handler_path: &Vec<String>) -> Option<&RateLimit> {
+        longest_prefix_match(&self.rate_limits, handler_path)
+    }
+
+    pub(crate) fn concurrency_limit_for(&self, handler_path: &Vec<String>) -> Option<&ConcurrencyLimit> {
+        longest_prefix_match(&self.concurrency_limits, handler_path)
+    }
+
+    pub(crate) fn timeout_for(&self, handler_path: &Vec<String>) -> Option<Duration> {
+        longest_prefix_match(&self.handler_timeouts, handler_path).copied().or(self.request_timeout)
+    }
+
+    pub(crate) fn cors_for(&self, handler_path: Option<&Vec<String>>) -> &Cors {
+        handler_path.and_then(|path| longest_prefix_match(&self.namespace_cors, path)).unwrap_or(&self.cors)
+    }
+
+    /// Refuse settings which can't be served safely, before the server is started.
+    pub(crate) fn validate(&self) -> Result<()> {
+        self.cors.validate()?;
+        for cors in self.namespace_cors.values() {
+            cors.validate()?;
+        }
+        Ok(())
+    }
+}
+
+/// Maximum request body sizes in bytes, `None` means unlimited.
+#[derive(Debug, Clone)]
+pub struct BodyLimits {
+    pub json: Option<usize>,
+    /// The whole urlencoded or multipart body, uploaded files included.
+    pub form: Option<usize>,
+    /// Each uploaded file.
+    pub form_file: Option<usize>,
+}
+
+impl Default for BodyLimits {
+
+    fn default() -> Self {
+        Self {
+            json: Some(262_144),
+            form: Some(10_485_760),
+            form_file: None,
+        }
+    }
+}
+
+/// Find the setting registered for the deepest namespace which contains `path`.
+pub(crate) fn longest_prefix_match<'a, T>(map: &'a BTreeMap<String, T>, path: &Vec<String>) -> Option<&'a T> {
+    for len in (1..=path.len()).rev() {
+        if let Some(value) = map.get(&path[0..len].join(".")) {
+            return Some(value);
+        }
+    }
+    None
+}
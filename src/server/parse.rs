@@ -1,96 +1,184 @@
 use std::io::Write;
+use actix_http::encoding::Decoder;
+use actix_http::error::PayloadError;
 use actix_multipart::Multipart;
-use actix_web::{FromRequest, HttpRequest, web};
-use futures_util::{StreamExt, TryStreamExt};
-use regex::Regex;
-use serde_json::{json, Value as JsonValue};
+use actix_web::{FromRequest, HttpMessage, HttpRequest, web};
+use actix_web::http::header::CONTENT_LENGTH;
+use actix_web::web::Bytes;
+use futures_util::{Stream, StreamExt, TryStreamExt};
+use serde_json::{json, Map, Value as JsonValue};
+use url::form_urlencoded;
 use teo_result::{Result, Error};
 use teo_runtime::error_runtime_ext::ErrorRuntimeExt;
+use crate::server::encoding::BodyEncoding;
+use crate::server::error::status_error;
+use crate::server::form::insert_form_value;
+use crate::server::options::ServerOptions;
+use crate::server::storage::ByteStream;
+use crate::server::upload::{track_stored_file, track_upload, upload_file_name};
+use crate::server::options::BodyLimits;
 
-pub(super) async fn parse_json_body(mut payload: web::Payload) -> Result<JsonValue> {
-    let mut body = web::BytesMut::new();
-    while let Some(chunk) = payload.next().await {
-        let chunk = chunk.unwrap();
-        // limit max size of in-memory payload
-        if (body.len() + chunk.len()) > 262_144usize {
-            return Err(Error::internal_server_error_message_only("memory overflow"));
-        }
-        body.extend_from_slice(&chunk);
-    }
-    let parsed_json_body_result: std::result::Result<JsonValue, serde_json::Error> = serde_json::from_slice(&body);
-    let parsed_json_body = match parsed_json_body_result {
-        Ok(b) => b,
-        Err(_) => {
-            return Err(Error::value_error_message_only("incorrect json format"));
-        }
-    };
+pub(super) async fn parse_json_body(http_request: &HttpRequest, payload: web::Payload, limits: &BodyLimits) -> Result<JsonValue> {
+    check_content_length(http_request, limits.json)?;
+    // bodies of other content types are read as JSON like before
+    let encoding = BodyEncoding::from_content_type(http_request.content_type()).unwrap_or(BodyEncoding::Json);
+    // compressed bodies are inflated as they're read, so the limit applies to the inflated size
+    let body = read_body(Decoder::from_headers(payload, http_request.headers()), limits.json).await?;
+    let parsed_json_body = encoding.decode(&body)?;
     if !parsed_json_body.is_object() {
         return Err(Error::value_error_message_only("expect json root object"));
     }
     Ok(parsed_json_body)
 }
 
-pub(super) async fn parse_form_body(http_request: HttpRequest, mut payload: web::Payload) -> Result<JsonValue> {
+pub(super) async fn parse_form_body(http_request: HttpRequest, mut payload: web::Payload, limits: &BodyLimits, options: &ServerOptions) -> Result<JsonValue> {
+    check_content_length(&http_request, limits.form)?;
+    if http_request.content_type() == "application/x-www-form-urlencoded" {
+        return parse_urlencoded_body(payload, limits).await;
+    }
+    let mut total_size = 0usize;
     let mut inner_payload = payload.into_inner();
     let multipart_result = Multipart::from_request(&http_request, &mut inner_payload).await;
     let mut multipart = match multipart_result {
         Ok(multipart) => multipart,
         Err(err) => return Err(Error::value_error_message_only("incorrect form format")),
     };
-    let mut result_value = json!({});
-    while let Some(mut field) = multipart.try_next().await.unwrap() {
+    let mut result = Map::new();
+    while let Some(mut field) = multipart.try_next().await.map_err(|_| Error::value_error_message_only("incorrect form format"))? {
         // A multipart/form-data stream has to contain `content_disposition`
         if let Some(filename) = field.content_disposition().get_filename().map(|f| f.to_owned()) {
-            let filepath = std::env::temp_dir().join(filename.clone()).to_str().unwrap().to_owned();
-            let filepath2 = filepath.clone();
-            // File::create is blocking operation, use threadpool
-            let mut f = web::block(move || std::fs::File::create(&filepath)).await.unwrap().unwrap();
-            // Field in turn is stream of *Bytes* object
-            while let Some(chunk) = field.try_next().await.unwrap() {
-                // filesystem operations are blocking, we have to use threadpool
-                f = web::block(move || f.write_all(&chunk).map(|_| f)).await.unwrap().unwrap();
-            }
-            let owned_field_name = field.name().to_owned();
-            if owned_field_name.ends_with("[]") {
-                let field_name_without_suffix = owned_field_name.strip_suffix("[]").unwrap();
-                if !result_value.as_object_mut().unwrap().contains_key(field_name_without_suffix) {
-                    result_value.as_object_mut().unwrap().insert(field_name_without_suffix.to_owned(), json!([]));
-                }
-                result_value.as_object_mut().unwrap().get_mut(field_name_without_suffix).unwrap().as_array_mut().unwrap().push(json!({
-                    "filepath": filepath2,
-                    "contentType": field.content_type().map(|c| c.to_string()),
-                    "filename": filename,
-                    "filenameExt": field.content_disposition().get_filename_ext().map(|e| e.to_string()),
-                }));
-            } else if owned_field_name.ends_with("]") {
-                let regex = Regex::new("(.*)\\[(.*)\\]").unwrap();
-                let found = regex.captures(&owned_field_name).unwrap();
-                let field_name = found.get(1).unwrap().as_str().to_owned();
-                let dict_name = found.get(2).unwrap().as_str().to_owned();
-                if !result_value.as_object_mut().unwrap().contains_key(&field_name) {
-                    result_value.as_object_mut().unwrap().insert(field_name.clone(), json!([]));
-                }
-                result_value.as_object_mut().unwrap().get_mut(&field_name).unwrap().as_object_mut().unwrap().insert(dict_name, json!({
-                    "filepath": filepath2,
-                    "contentType": field.content_type().map(|c| c.to_string()),
-                    "filename": filename,
-                    "filenameExt": field.content_disposition().get_filename_ext().map(|e| e.to_string()),
+            // the client supplied filename is never used as a path, only its extension is kept
+            let file_name = upload_file_name(&filename);
+            let filepath = if let Some(storage) = &options.file_storage {
+                // the file is streamed into the storage as it's received
+                let content_type = field.content_type().map(|c| c.to_string());
+                track_stored_file(&http_request, storage.clone(), file_name.clone());
+                let (sender, receiver) = tokio::sync::mpsc::channel::<Result<Bytes>>(4);
+                let content: ByteStream = Box::pin(futures_util::stream::unfold(receiver, |mut receiver| async move {
+                    receiver.recv().await.map(|chunk| (chunk, receiver))
                 }));
+                let receive = async {
+                    let mut file_size = 0usize;
+                    while let Some(chunk) = field.try_next().await.map_err(|_| Error::value_error_message_only("incorrect form format"))? {
+                        file_size += chunk.len();
+                        total_size += chunk.len();
+                        let checked = check_limit(file_size, limits.form_file).and_then(|_| check_limit(total_size, limits.form));
+                        if let Err(err) = checked {
+                            // the storage discards what it has received
+                            let _ = sender.send(Err(Error::value_error_message_only("upload is incomplete"))).await;
+                            return Err(err);
+                        }
+                        if sender.send(Ok(chunk)).await.is_err() {
+                            // the storage failed, its error is returned
+                            break;
+                        }
+                    }
+                    drop(sender);
+                    Ok(())
+                };
+                let (received, stored) = futures_util::future::join(receive, storage.put_stream(&file_name, content_type.as_deref(), content)).await;
+                received?;
+                stored?;
+                storage.location(&file_name)
             } else {
-                result_value.as_object_mut().unwrap().insert(field.name().to_owned(), json!({
-                    "filepath": filepath2,
-                    "contentType": field.content_type().map(|c| c.to_string()),
-                    "filename": filename,
-                    "filenameExt": field.content_disposition().get_filename_ext().map(|e| e.to_string()),
-                }));
-            }
+                let path = options.upload_dir().join(&file_name);
+                let filepath = path.to_str().ok_or_else(|| Error::internal_server_error_message_only("invalid upload directory"))?.to_owned();
+                track_upload(&http_request, path.clone());
+                // File::create is blocking operation, use threadpool
+                let create_path = path.clone();
+                let mut f = web::block(move || {
+                    std::fs::create_dir_all(create_path.parent().unwrap())?;
+                    std::fs::File::create(&create_path)
+                }).await.map_err(|_| upload_error())?.map_err(|_| upload_error())?;
+                // Field in turn is stream of *Bytes* object
+                let mut file_size = 0usize;
+                while let Some(chunk) = field.try_next().await.map_err(|_| Error::value_error_message_only("incorrect form format"))? {
+                    file_size += chunk.len();
+                    total_size += chunk.len();
+                    check_limit(file_size, limits.form_file)?;
+                    check_limit(total_size, limits.form)?;
+                    // filesystem operations are blocking, we have to use threadpool
+                    f = web::block(move || f.write_all(&chunk).map(|_| f)).await.map_err(|_| upload_error())?.map_err(|_| upload_error())?;
+                }
+                filepath
+            };
+            let file_value = json!({
+                "filepath": filepath,
+                "contentType": field.content_type().map(|c| c.to_string()),
+                "filename": filename,
+                "filenameExt": field.content_disposition().get_filename_ext().map(|e| e.to_string()),
+            });
+            insert_form_value(&mut result, field.name(), file_value)?;
         } else {
             let mut body = web::BytesMut::new();
-            while let Some(chunk) = field.try_next().await.unwrap() {
+            while let Some(chunk) = field.try_next().await.map_err(|_| Error::value_error_message_only("incorrect form format"))? {
+                total_size += chunk.len();
+                check_limit(total_size, limits.form)?;
                 body.extend_from_slice(&chunk);
             }
-            result_value.as_object_mut().unwrap().insert(field.name().to_owned(), serde_json::Value::String(String::from_utf8(body.as_ref().to_vec()).unwrap()));
+            let text = String::from_utf8(body.as_ref().to_vec()).map_err(|_| Error::value_error_message_only(format!("form field `{}` is not valid UTF-8", field.name())))?;
+            insert_form_value(&mut result, field.name(), JsonValue::String(text))?;
         }
     }
-    Ok(result_value)
+    Ok(JsonValue::Object(result))
+}
+
+async fn parse_urlencoded_body(payload: web::Payload, limits: &BodyLimits) -> Result<JsonValue> {
+    let body = read_body(payload, limits.form).await?;
+    let mut result = Map::new();
+    for (name, value) in form_urlencoded::parse(&body) {
+        insert_form_value(&mut result, &name, JsonValue::String(value.into_owned()))?;
+    }
+    Ok(JsonValue::Object(result))
+}
+
+async fn read_body<S>(payload: S, limit: Option<usize>) -> Result<web::BytesMut> where S: Stream<Item = std::result::Result<Bytes, PayloadError>> {
+    futures_util::pin_mut!(payload);
+    let mut body = web::BytesMut::new();
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk.map_err(|_| Error::value_error_message_only("incorrect request body"))?;
+        // limit max size of in-memory payload
+        check_limit(body.len() + chunk.len(), limit)?;
+        body.extend_from_slice(&chunk);
+    }
+    Ok(body)
+}
+
+fn upload_error() -> Error {
+    Error::internal_server_error_message_only("cannot save uploaded file")
+}
+
+fn check_content_length(http_request: &HttpRequest, limit: Option<usize>) -> Result<()> {
+    let content_length = http_request.headers().get(CONTENT_LENGTH).and_then(|v| v.to_str().ok()).and_then(|v| v.parse::<usize>().ok());
+    match content_length {
+        Some(content_length) => check_limit(content_length, limit),
+        None => Ok(()),
+    }
+}
+
+fn check_limit(size: usize, limit: Option<usize>) -> Result<()> {
+    match limit {
+        Some(limit) if size > limit => Err(status_error(413, format!("payload too large, the limit is {} bytes", limit))),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::server::options::BodyLimits;
+    use super::check_limit;
+
+    #[test]
+    fn sizes_over_the_limit_are_rejected_with_413() {
+        assert!(check_limit(10, Some(10)).is_ok());
+        assert!(check_limit(usize::MAX, None).is_ok());
+        assert_eq!(check_limit(11, Some(10)).unwrap_err().code, Some(413));
+    }
+
+    #[test]
+    fn form_bodies_are_limited_by_default() {
+        let limits = BodyLimits::default();
+        assert!(limits.json.is_some());
+        assert!(limits.form.is_some());
+    }
 }
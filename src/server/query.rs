@@ -0,0 +1,195 @@
+use std::collections::HashMap;
+use serde_json::{Map, Number, Value as JsonValue};
+use teo_parser::r#type::Type;
+use teo_result::Result;
+use teo_runtime::namespace::Namespace;
+use url::form_urlencoded;
+use crate::server::form::insert_form_value;
+
+/// Decode a query string into a JSON object. Both `address[city]=x` and `address.city=x` are
+/// nested, and string values are coerced to the types of `input_type` when it's given.
+/// Repeated keys like `id=1&id=2` are collected when `input_type` declares an array there.
+pub(crate) fn parse_query_string(query_string: &str, input_type: Option<&Type>, main_namespace: &Namespace) -> Result<JsonValue> {
+    if query_string.is_empty() {
+        return Ok(JsonValue::Null);
+    }
+    let pairs: Vec<(String, String)> = form_urlencoded::parse(query_string.as_bytes()).map(|(name, value)| (dots_to_brackets(&name), value.into_owned())).collect();
+    let mut counts: HashMap<&str, usize> = HashMap::new();
+    for (name, _) in &pairs {
+        *counts.entry(name.as_str()).or_insert(0) += 1;
+    }
+    let mut result = Map::new();
+    for (name, value) in &pairs {
+        let repeated_array = counts[name.as_str()] > 1 && input_type.map_or(false, |t| is_array_at(t, &field_path(name), main_namespace));
+        let name = if repeated_array { format!("{}[]", name) } else { name.clone() };
+        insert_form_value(&mut result, &name, JsonValue::String(value.clone()))?;
+    }
+    let value = JsonValue::Object(result);
+    Ok(match input_type {
+        Some(input_type) => coerce(value, input_type, main_namespace),
+        None => value,
+    })
+}
+
+/// `a.b[0].c` is rewritten as `a[b][0][c]`.
+fn dots_to_brackets(name: &str) -> String {
+    if !name.contains('.') {
+        return name.to_owned();
+    }
+    let mut result = String::new();
+    let mut head = true;
+    let mut rest = name;
+    while !rest.is_empty() {
+        if let Some(inner) = rest.strip_prefix('[') {
+            match inner.find(']') {
+                Some(end) => {
+                    result.push_str(&rest[0..end + 2]);
+                    rest = &inner[end + 1..];
+                }
+                None => return name.to_owned(),
+            }
+        } else if let Some(after_dot) = rest.strip_prefix('.') {
+            rest = after_dot;
+        } else {
+            let end = rest.find(|c| c == '.' || c == '[').unwrap_or(rest.len());
+            if head {
+                result.push_str(&rest[0..end]);
+            } else {
+                result.push('[');
+                result.push_str(&rest[0..end]);
+                result.push(']');
+            }
+            rest = &rest[end..];
+        }
+        head = false;
+    }
+    result
+}
+
+/// The keys of a bracketed field name, `a[b][0]` is `["a", "b", "0"]`.
+fn field_path(name: &str) -> Vec<&str> {
+    name.split('[').map(|segment| segment.trim_end_matches(']')).collect()
+}
+
+/// Whether the field at `path` in `t` is declared as an array.
+fn is_array_at(t: &Type, path: &[&str], main_namespace: &Namespace) -> bool {
+    let t = t.unwrap_optional();
+    let (key, rest) = match path.split_first() {
+        Some(first) => first,
+        None => return t.as_array().is_some(),
+    };
+    if let Some(inner) = t.as_dictionary() {
+        is_array_at(inner, rest, main_namespace)
+    } else if let Some((reference, _)) = t.as_interface_object() {
+        match main_namespace.interface_at_path(&reference.str_path()).and_then(|interface| interface.fields.get(*key)) {
+            Some(field) => is_array_at(&field.r#type, rest, main_namespace),
+            None => false,
+        }
+    } else if let Some(inner) = t.as_array() {
+        key.parse::<usize>().is_ok() && is_array_at(inner, rest, main_namespace)
+    } else {
+        false
+    }
+}
+
+fn coerce(value: JsonValue, t: &Type, main_namespace: &Namespace) -> JsonValue {
+    let t = t.unwrap_optional();
+    match value {
+        JsonValue::String(string) => coerce_string(string, t, main_namespace),
+        JsonValue::Array(array) => match t.as_array() {
+            Some(inner) => JsonValue::Array(array.into_iter().map(|v| coerce(v, inner, main_namespace)).collect()),
+            None => JsonValue::Array(array),
+        },
+        JsonValue::Object(object) => if let Some(inner) = t.as_dictionary() {
+            JsonValue::Object(object.into_iter().map(|(k, v)| (k, coerce(v, inner, main_namespace))).collect())
+        } else if let Some((reference, _)) = t.as_interface_object() {
+            match main_namespace.interface_at_path(&reference.str_path()) {
+                Some(interface) => JsonValue::Object(object.into_iter().map(|(k, v)| {
+                    let value = match interface.fields.get(&k) {
+                        Some(field) => coerce(v, &field.r#type, main_namespace),
+                        None => v,
+                    };
+                    (k, value)
+                }).collect()),
+                None => JsonValue::Object(object),
+            }
+        } else {
+            JsonValue::Object(object)
+        },
+        value => value,
+    }
+}
+
+fn coerce_string(string: String, t: &Type, main_namespace: &Namespace) -> JsonValue {
+    match t {
+        Type::Int | Type::Int64 => match string.parse::<i64>() {
+            Ok(i) => JsonValue::Number(Number::from(i)),
+            Err(_) => JsonValue::String(string),
+        },
+        Type::Float | Type::Float32 => match string.parse::<f64>().ok().and_then(Number::from_f64) {
+            Some(f) => JsonValue::Number(f),
+            None => JsonValue::String(string),
+        },
+        Type::Bool => match string.as_str() {
+            "true" | "1" => JsonValue::Bool(true),
+            "false" | "0" => JsonValue::Bool(false),
+            _ => JsonValue::String(string),
+        },
+        // `?ids=1` for an array input is a single element array
+        Type::Array(inner) => JsonValue::Array(vec![coerce_string(string, inner.unwrap_optional(), main_namespace)]),
+        _ => JsonValue::String(string),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use teo_parser::r#type::Type;
+    use teo_runtime::namespace::Namespace;
+    use super::{coerce, dots_to_brackets, field_path, is_array_at, parse_query_string};
+
+    #[test]
+    fn dots_are_rewritten_as_brackets() {
+        assert_eq!(dots_to_brackets("name"), "name");
+        assert_eq!(dots_to_brackets("a.b"), "a[b]");
+        assert_eq!(dots_to_brackets("a.b[0].c"), "a[b][0][c]");
+        assert_eq!(dots_to_brackets("a[b].c"), "a[b][c]");
+        assert_eq!(dots_to_brackets("a.b[0"), "a.b[0");
+    }
+
+    #[test]
+    fn field_paths() {
+        assert_eq!(field_path("id"), vec!["id"]);
+        assert_eq!(field_path("a[b][0]"), vec!["a", "b", "0"]);
+    }
+
+    #[test]
+    fn arrays_are_found_through_dictionaries() {
+        let namespace = Namespace::main();
+        let t = Type::Dictionary(Box::new(Type::Array(Box::new(Type::Int))));
+        assert!(is_array_at(&t, &["ids"], &namespace));
+        assert!(!is_array_at(&Type::Dictionary(Box::new(Type::Int)), &["ids"], &namespace));
+    }
+
+    #[test]
+    fn strings_are_coerced_to_declared_types() {
+        let namespace = Namespace::main();
+        assert_eq!(coerce(json!("1"), &Type::Int, &namespace), json!(1));
+        assert_eq!(coerce(json!("1.5"), &Type::Float, &namespace), json!(1.5));
+        assert_eq!(coerce(json!("true"), &Type::Bool, &namespace), json!(true));
+        assert_eq!(coerce(json!("x"), &Type::Int, &namespace), json!("x"));
+        assert_eq!(coerce(json!("1"), &Type::Optional(Box::new(Type::Int)), &namespace), json!(1));
+        assert_eq!(coerce(json!("1"), &Type::Array(Box::new(Type::Int)), &namespace), json!([1]));
+        assert_eq!(coerce(json!(["1", "2"]), &Type::Array(Box::new(Type::Int)), &namespace), json!([1, 2]));
+        assert_eq!(coerce(json!({ "a": "1" }), &Type::Dictionary(Box::new(Type::Int)), &namespace), json!({ "a": 1 }));
+    }
+
+    #[test]
+    fn repeated_keys_collect_into_declared_arrays() {
+        let namespace = Namespace::main();
+        let ids = Type::Dictionary(Box::new(Type::Array(Box::new(Type::Int))));
+        assert_eq!(parse_query_string("ids=1&ids=2", Some(&ids), &namespace).unwrap(), json!({ "ids": [1, 2] }));
+        let id = Type::Dictionary(Box::new(Type::Int));
+        assert_eq!(parse_query_string("id=1&id=2", Some(&id), &namespace).unwrap(), json!({ "id": 2 }));
+    }
+}
@@ -0,0 +1,387 @@
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use actix_http::header::{HeaderValue, RETRY_AFTER};
+use actix_web::{HttpRequest, HttpResponse, ResponseError};
+use async_trait::async_trait;
+use teo_result::{Error, Result};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use crate::server::error::{status_error, WrapError};
+use crate::server::options::ServerOptions;
+use crate::server::request_id::current_identity;
+
+/// What requests are counted together.
+#[derive(Debug, Clone)]
+pub enum RateLimitKey {
+    /// The address of the peer. Behind a proxy, use the header the proxy sets instead.
+    ClientIp,
+    /// The value of a request header, requests without it share one bucket.
+    Header(String),
+    /// The identity recorded with `set_log_identity` by the middlewares, the client IP for
+    /// anonymous requests. The server doesn't know who is signed in otherwise, every request
+    /// is anonymous unless the application calls `set_log_identity`.
+    Identity,
+}
+
+/// A token bucket which holds `capacity` requests and is refilled completely over `per`.
+#[derive(Debug, Clone)]
+pub struct RateLimit {
+    pub key: RateLimitKey,
+    pub capacity: u32,
+    pub per: Duration,
+}
+
+impl RateLimit {
+
+    pub fn new(key: RateLimitKey, capacity: u32, per: Duration) -> Self {
+        Self { key, capacity, per }
+    }
+
+    fn tokens_per_second(&self) -> f64 {
+        self.capacity as f64 / self.per.as_secs_f64()
+    }
+}
+
+/// Where token buckets are kept. Implement it over a shared store when several servers should
+/// count together.
+#[async_trait]
+pub trait RateLimitStore: Debug + Send + Sync {
+
+    /// Take a token from the bucket `key`. Returns how long until a token is available when
+    /// the bucket is empty.
+    async fn take(&self, key: &str, limit: &RateLimit) -> Result<Option<Duration>>;
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    full_after: Duration,
+}
+
+/// Keep token buckets in this process. Buckets which are full again are dropped every
+/// `SWEEP_INTERVAL` new buckets, and the least recently used ones when there are more than
+/// `max_buckets`.
+#[derive(Debug)]
+pub struct MemoryRateLimitStore {
+    buckets: Mutex<Buckets>,
+    max_buckets: usize,
+}
+
+#[derive(Debug, Default)]
+struct Buckets {
+    map: HashMap<String, Bucket>,
+    inserted: usize,
+}
+
+const SWEEP_INTERVAL: usize = 1024;
+
+impl Default for MemoryRateLimitStore {
+
+    fn default() -> Self {
+        Self::with_max_buckets(100_000)
+    }
+}
+
+impl MemoryRateLimitStore {
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_max_buckets(max_buckets: usize) -> Self {
+        Self {
+            buckets: Mutex::new(Buckets::default()),
+            max_buckets: max_buckets.max(1),
+        }
+    }
+}
+
+impl Buckets {
+
+    fn make_room(&mut self, now: Instant, max_buckets: usize) {
+        self.inserted += 1;
+        if self.inserted >= SWEEP_INTERVAL || self.map.len() >= max_buckets {
+            self.inserted = 0;
+            // buckets which are full again are the same as missing ones
+            self.map.retain(|_, bucket| now.duration_since(bucket.updated) < bucket.full_after);
+        }
+        if self.map.len() >= max_buckets {
+            // leave some room so this doesn't run again for the next request
+            let keep = max_buckets - max_buckets / 10 - 1;
+            let mut updated: Vec<Instant> = self.map.values().map(|bucket| bucket.updated).collect();
+            updated.sort_unstable_by(|a, b| b.cmp(a));
+            let oldest_kept = updated[keep.min(updated.len() - 1)];
+            self.map.retain(|_, bucket| bucket.updated > oldest_kept);
+        }
+    }
+}
+
+#[async_trait]
+impl RateLimitStore for MemoryRateLimitStore {
+
+    async fn take(&self, key: &str, limit: &RateLimit) -> Result<Option<Duration>> {
+        let now = Instant::now();
+        let capacity = limit.capacity as f64;
+        let mut buckets = self.buckets.lock().unwrap();
+        if !buckets.map.contains_key(key) {
+            buckets.make_room(now, self.max_buckets);
+        }
+        let bucket = buckets.map.entry(key.to_owned()).or_insert(Bucket { tokens: capacity, updated: now, full_after: limit.per });
+        bucket.tokens = (bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * limit.tokens_per_second()).min(capacity);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(None)
+        } else {
+            Ok(Some(Duration::from_secs_f64((1.0 - bucket.tokens) / limit.tokens_per_second())))
+        }
+    }
+}
+
+/// How many requests a handler serves at once.
+#[derive(Debug, Clone)]
+pub struct ConcurrencyLimit {
+    semaphore: Arc<Semaphore>,
+    /// How long excess requests wait for a slot. They are rejected at once when `None`.
+    pub queue_timeout: Option<Duration>,
+}
+
+impl ConcurrencyLimit {
+
+    pub fn new(max: usize) -> Self {
+        Self {
+            semaphore: Arc::new(Semaphore::new(max)),
+            queue_timeout: None,
+        }
+    }
+
+    pub fn queue(mut self, timeout: Duration) -> Self {
+        self.queue_timeout = Some(timeout);
+        self
+    }
+
+    async fn acquire(&self) -> Option<OwnedSemaphorePermit> {
+        match self.queue_timeout {
+            None => self.semaphore.clone().try_acquire_owned().ok(),
+            Some(timeout) => tokio::time::timeout(timeout, self.semaphore.clone().acquire_owned()).await.ok()?.ok(),
+        }
+    }
+}
+
+/// Apply the limits of `handler_path` before the request body is read, the permit has to be
+/// held until the response is produced. Identity limits are checked later by `IdentityLimit`.
+pub(crate) async fn limit_request(options: &'static ServerOptions, handler_path: &Vec<String>, http_request: &HttpRequest) -> std::result::Result<Option<OwnedSemaphorePermit>, HttpResponse> {
+    let permit = match options.concurrency_limit_for(handler_path) {
+        Some(limit) => Some(limit.acquire().await.ok_or_else(overloaded)?),
+        None => None,
+    };
+    take_token(options, handler_path, http_request).await?;
+    Ok(permit)
+}
+
+/// Apply the limits of the handlers a batch runs. Every operation takes a token, while a batch
+/// counts once against each concurrency limit since its operations run one after another.
+/// Concurrency limits are acquired in the order of the handler paths they're set for, so that
+/// batches waiting for each other's permits can't deadlock.
+pub(crate) async fn limit_batch(options: &'static ServerOptions, handler_paths: &[Vec<String>], http_request: &HttpRequest) -> std::result::Result<Vec<OwnedSemaphorePermit>, HttpResponse> {
+    let used: Vec<&ConcurrencyLimit> = handler_paths.iter().filter_map(|handler_path| options.concurrency_limit_for(handler_path)).collect();
+    let mut permits = vec![];
+    // the map is sorted by handler path, the handlers of a group share one limit
+    for limit in options.concurrency_limits.values() {
+        if used.iter().any(|u| std::ptr::eq(*u, limit)) {
+            permits.push(limit.acquire().await.ok_or_else(overloaded)?);
+        }
+    }
+    for handler_path in handler_paths {
+        take_token(options, handler_path, http_request).await?;
+    }
+    Ok(permits)
+}
+
+async fn take_token(options: &'static ServerOptions, handler_path: &Vec<String>, http_request: &HttpRequest) -> std::result::Result<(), HttpResponse> {
+    if let Some(limit) = options.rate_limit_for(handler_path) {
+        let client = match &limit.key {
+            RateLimitKey::ClientIp => client_ip(http_request),
+            RateLimitKey::Header(name) => http_request.headers().get(name.as_str()).and_then(|v| v.to_str().ok()).unwrap_or("").to_owned(),
+            RateLimitKey::Identity => return Ok(()),
+        };
+        match options.rate_limit_store.take(&bucket_key(handler_path, &client), limit).await {
+            Ok(None) => (),
+            Ok(Some(wait)) => return Err(too_many_requests(wait)),
+            Err(err) => return Err(WrapError::from(err).error_response()),
+        }
+    }
+    Ok(())
+}
+
+/// An identity rate limit, checked right before the handler runs since the identity is only
+/// known after the middlewares.
+#[derive(Debug, Clone)]
+pub(crate) struct IdentityLimit {
+    limit: &'static RateLimit,
+    store: &'static dyn RateLimitStore,
+    handler_path: Vec<String>,
+    client_ip: String,
+    rejected: Arc<Mutex<Option<Duration>>>,
+}
+
+impl IdentityLimit {
+
+    pub(crate) fn new(options: &'static ServerOptions, handler_path: &Vec<String>, http_request: &HttpRequest) -> Option<Self> {
+        let limit = options.rate_limit_for(handler_path)?;
+        match limit.key {
+            RateLimitKey::Identity => Some(Self {
+                limit,
+                store: options.rate_limit_store.as_ref(),
+                handler_path: handler_path.clone(),
+                client_ip: client_ip(http_request),
+                rejected: Arc::new(Mutex::new(None)),
+            }),
+            _ => None,
+        }
+    }
+
+    pub(crate) async fn check(&self) -> Result<()> {
+        let client = current_identity().unwrap_or_else(|| self.client_ip.clone());
+        if let Some(wait) = self.store.take(&bucket_key(&self.handler_path, &client), self.limit).await? {
+            *self.rejected.lock().unwrap() = Some(wait);
+            Err(too_many_requests_error())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// The 429 response when `check` rejected the request.
+    pub(crate) fn rejected_response(&self) -> Option<HttpResponse> {
+        self.rejected.lock().unwrap().map(too_many_requests)
+    }
+}
+
+fn bucket_key(handler_path: &Vec<String>, client: &str) -> String {
+    format!("{}:{}", handler_path.join("."), client)
+}
+
+fn client_ip(http_request: &HttpRequest) -> String {
+    http_request.peer_addr().map(|addr| addr.ip().to_string()).unwrap_or_default()
+}
+
+fn too_many_requests_error() -> Error {
+    status_error(429, "too many requests")
+}
+
+fn too_many_requests(wait: Duration) -> HttpResponse {
+    with_retry_after(WrapError::from(too_many_requests_error()).error_response(), wait)
+}
+
+fn overloaded() -> HttpResponse {
+    with_retry_after(WrapError::from(status_error(503, "too many concurrent requests")).error_response(), Duration::from_secs(1))
+}
+
+fn with_retry_after(mut response: HttpResponse, wait: Duration) -> HttpResponse {
+    let seconds = wait.as_secs_f64().ceil().max(1.0) as u64;
+    response.headers_mut().insert(RETRY_AFTER, HeaderValue::from(seconds));
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use actix_http::StatusCode;
+    use actix_web::test::TestRequest;
+    use crate::server::options::ServerOptions;
+    use super::{limit_batch, ConcurrencyLimit, MemoryRateLimitStore, RateLimit, RateLimitKey, RateLimitStore, SWEEP_INTERVAL};
+
+    fn path(path: &str) -> Vec<String> {
+        path.split('.').map(|s| s.to_owned()).collect()
+    }
+
+    #[tokio::test]
+    async fn buckets_run_empty_and_refill() {
+        let store = MemoryRateLimitStore::new();
+        let limit = RateLimit::new(RateLimitKey::ClientIp, 2, Duration::from_secs(60));
+        assert_eq!(store.take("a", &limit).await.unwrap(), None);
+        assert_eq!(store.take("a", &limit).await.unwrap(), None);
+        let wait = store.take("a", &limit).await.unwrap().unwrap();
+        assert!(wait > Duration::from_secs(29) && wait <= Duration::from_secs(30));
+        assert_eq!(store.take("b", &limit).await.unwrap(), None);
+        let limit = RateLimit::new(RateLimitKey::ClientIp, 1, Duration::from_millis(50));
+        assert_eq!(store.take("c", &limit).await.unwrap(), None);
+        assert!(store.take("c", &limit).await.unwrap().is_some());
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert_eq!(store.take("c", &limit).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn full_buckets_are_swept() {
+        let store = MemoryRateLimitStore::new();
+        let limit = RateLimit::new(RateLimitKey::ClientIp, 1, Duration::from_millis(1));
+        for index in 0..SWEEP_INTERVAL - 1 {
+            store.take(&index.to_string(), &limit).await.unwrap();
+        }
+        assert_eq!(store.buckets.lock().unwrap().map.len(), SWEEP_INTERVAL - 1);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        store.take("last", &limit).await.unwrap();
+        assert_eq!(store.buckets.lock().unwrap().map.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn the_number_of_buckets_is_capped() {
+        let store = MemoryRateLimitStore::with_max_buckets(10);
+        let limit = RateLimit::new(RateLimitKey::ClientIp, 1, Duration::from_secs(60));
+        for index in 0..25 {
+            store.take(&index.to_string(), &limit).await.unwrap();
+            assert!(store.buckets.lock().unwrap().map.len() <= 10);
+        }
+        // the most recent client keeps its empty bucket
+        assert!(store.take("24", &limit).await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn batches_take_a_token_per_operation() {
+        let mut options = ServerOptions::new();
+        options.set_rate_limit("User", RateLimit::new(RateLimitKey::ClientIp, 2, Duration::from_secs(60)));
+        let options: &'static ServerOptions = Box::leak(Box::new(options));
+        let http_request = TestRequest::default().to_http_request();
+        assert!(limit_batch(options, &[path("User.create"), path("User.update")], &http_request).await.is_ok());
+        let response = limit_batch(options, &[path("User.create")], &http_request).await.unwrap_err();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[tokio::test]
+    async fn batches_hold_one_permit_per_concurrency_limit() {
+        let mut options = ServerOptions::new();
+        options.set_concurrency_limit("User", ConcurrencyLimit::new(1));
+        let options: &'static ServerOptions = Box::leak(Box::new(options));
+        let http_request = TestRequest::default().to_http_request();
+        let permits = limit_batch(options, &[path("User.create"), path("User.update")], &http_request).await.unwrap();
+        assert_eq!(permits.len(), 1);
+        let response = limit_batch(options, &[path("User.create")], &http_request).await.unwrap_err();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        drop(permits);
+        assert!(limit_batch(options, &[path("User.create")], &http_request).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn batches_acquire_concurrency_limits_in_handler_path_order() {
+        let mut options = ServerOptions::new();
+        options.set_concurrency_limit("Post", ConcurrencyLimit::new(1));
+        options.set_concurrency_limit("User", ConcurrencyLimit::new(1).queue(Duration::from_secs(5)));
+        let options: &'static ServerOptions = Box::leak(Box::new(options));
+        let http_request = TestRequest::default().to_http_request();
+        let http_request = &http_request;
+        let user = limit_batch(options, &[path("User.create")], http_request).await.unwrap();
+        // `Post` comes first even though the batch lists it last
+        let batch = limit_batch(options, &[path("User.create"), path("Post.create")], http_request);
+        let check = async move {
+            tokio::task::yield_now().await;
+            let response = limit_batch(options, &[path("Post.create")], http_request).await.unwrap_err();
+            drop(user);
+            response.status()
+        };
+        let (batch, status) = futures_util::future::join(batch, check).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(batch.unwrap().len(), 2);
+    }
+}
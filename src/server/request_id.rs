@@ -0,0 +1,112 @@
+use std::cell::RefCell;
+use actix_http::header::{HeaderMap, HeaderName, HeaderValue};
+use teo_runtime::request::Request;
+use uuid::Uuid;
+use crate::server::stream::StreamOwner;
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+struct RequestScope {
+    id: String,
+    identity: RefCell<Option<String>>,
+    streams: StreamOwner,
+}
+
+tokio::task_local! {
+    static CURRENT_REQUEST: RequestScope;
+}
+
+/// The ID of a request, stored in the request extensions.
+#[derive(Debug, Clone)]
+pub(crate) struct RequestId(pub(crate) String);
+
+/// Request IDs are written to the `X-Request-Id` request header before handlers run.
+pub trait RequestIdExt {
+
+    fn request_id(&self) -> Option<String>;
+}
+
+impl RequestIdExt for Request {
+
+    fn request_id(&self) -> Option<String> {
+        self.headers().get(REQUEST_ID_HEADER).map(|id| id.to_owned())
+    }
+}
+
+/// The ID sent by the client or proxy, or a new one when it's missing or malformed. The
+/// request headers are updated to contain it.
+pub(crate) fn assign_request_id(headers: &mut HeaderMap) -> String {
+    let incoming = headers.get(REQUEST_ID_HEADER).and_then(|id| id.to_str().ok()).filter(|id| is_valid(id)).map(|id| id.to_owned());
+    match incoming {
+        Some(id) => id,
+        None => {
+            let id = Uuid::new_v4().to_string();
+            headers.insert(HeaderName::from_static(REQUEST_ID_HEADER), HeaderValue::from_str(&id).unwrap());
+            id
+        }
+    }
+}
+
+/// Run `future` with `request_id` as the ID of the current request.
+pub(crate) async fn with_request_id<F>(request_id: String, future: F) -> F::Output where F: std::future::Future {
+    CURRENT_REQUEST.scope(RequestScope { id: request_id, identity: RefCell::new(None), streams: StreamOwner::default() }, future).await
+}
+
+/// The ID of the request being handled on this task.
+pub(crate) fn current_request_id() -> Option<String> {
+    CURRENT_REQUEST.try_with(|scope| scope.id.clone()).ok()
+}
+
+pub(crate) fn set_current_identity(identity: String) {
+    let _ = CURRENT_REQUEST.try_with(|scope| *scope.identity.borrow_mut() = Some(identity));
+}
+
+pub(crate) fn current_identity() -> Option<String> {
+    CURRENT_REQUEST.try_with(|scope| scope.identity.borrow().clone()).ok().flatten()
+}
+
+/// Remove the stream `id` when the current request ends, unless a response has taken it.
+pub(crate) fn own_stream(id: String) {
+    let _ = CURRENT_REQUEST.try_with(|scope| scope.streams.own(id));
+}
+
+fn is_valid(id: &str) -> bool {
+    !id.is_empty() && id.len() <= 128 && id.bytes().all(|b| b.is_ascii_graphic())
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_http::header::{HeaderMap, HeaderName, HeaderValue};
+    use super::{assign_request_id, current_identity, current_request_id, set_current_identity, with_request_id, REQUEST_ID_HEADER};
+
+    #[test]
+    fn incoming_ids_are_kept() {
+        let mut headers = HeaderMap::new();
+        headers.insert(HeaderName::from_static(REQUEST_ID_HEADER), HeaderValue::from_static("abc-123"));
+        assert_eq!(assign_request_id(&mut headers), "abc-123");
+    }
+
+    #[test]
+    fn missing_or_malformed_ids_are_replaced() {
+        let mut headers = HeaderMap::new();
+        let id = assign_request_id(&mut headers);
+        assert_eq!(headers.get(REQUEST_ID_HEADER).unwrap(), id.as_str());
+        let mut headers = HeaderMap::new();
+        headers.insert(HeaderName::from_static(REQUEST_ID_HEADER), HeaderValue::from_static("has space"));
+        let id = assign_request_id(&mut headers);
+        assert_ne!(id, "has space");
+        assert_eq!(headers.get(REQUEST_ID_HEADER).unwrap(), id.as_str());
+    }
+
+    #[tokio::test]
+    async fn ids_and_identities_are_scoped_to_the_request() {
+        assert!(current_request_id().is_none());
+        let (id, identity) = with_request_id("abc".to_owned(), async {
+            set_current_identity("user-1".to_owned());
+            (current_request_id(), current_identity())
+        }).await;
+        assert_eq!(id.as_deref(), Some("abc"));
+        assert_eq!(identity.as_deref(), Some("user-1"));
+        assert!(current_identity().is_none());
+    }
+}
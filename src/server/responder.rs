@@ -1,8 +1,22 @@
-use actix_web::{HttpRequest, HttpResponse};
-use actix_web::http::StatusCode;
+use actix_web::{HttpRequest, HttpResponse, HttpResponseBuilder, ResponseError};
+use actix_web::http::{Method, StatusCode};
+use actix_web::http::header::{HttpDate, ACCEPT, ACCEPT_RANGES, CONTENT_RANGE, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, IF_RANGE, LAST_MODIFIED, RANGE, VARY};
+use actix_web::web::Bytes;
+use ring::digest;
+use teo_result::Error;
+use teo_runtime::error_runtime_ext::ErrorRuntimeExt;
 use teo_runtime::response::body::BodyInner;
 use teo_runtime::response::Response;
-use actix_files::NamedFile;
+use actix_files::{file_extension_to_mime, NamedFile};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::SystemTime;
+use crate::server::encoding::BodyEncoding;
+use crate::server::error::WrapError;
+use crate::server::options::ServerOptions;
+use crate::server::storage::{ByteStream, FileStorage, StoredObject};
+use crate::server::stream::{take_stream, STREAM_HEADER};
+use futures_util::{Stream, StreamExt};
 
 pub trait IntoHttpResponse {
     fn into_http_response(self, http_request: HttpRequest) -> HttpResponse;
@@ -11,22 +25,301 @@ pub trait IntoHttpResponse {
 impl IntoHttpResponse for Response {
 
     fn into_http_response(self, http_request: HttpRequest) -> HttpResponse {
-        let mut builder = HttpResponse::Ok();
-        builder.status(StatusCode::from_u16(self.code()).unwrap());
-        for key in self.headers().keys() {
-            builder.insert_header((key.clone(), self.headers().get(&key).unwrap().as_str()));
+        let mut builder = response_builder(&self);
+        if let Some(stream) = self.headers().get(STREAM_HEADER).and_then(|id| take_stream(id.as_str())) {
+            return builder.streaming(stream.map(|chunk| chunk.map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, format!("{}", err)))));
         }
         match self.body().inner.as_ref() {
             BodyInner::Empty => (),
             BodyInner::String(content) => return builder.body(content.to_string()),
-            BodyInner::File(file) => return NamedFile::open(file).unwrap().into_response(&http_request),
+            BodyInner::File(file) => {
+                // named files answer conditional and range requests themselves
+                return match NamedFile::open(file) {
+                    Ok(named_file) => named_file.into_response(&http_request),
+                    Err(_) => WrapError::from(Error::not_found_message_only()).error_response(),
+                }
+            },
             BodyInner::Teon(value) => {
-                builder.content_type("application/json");
-                let json_value = serde_json::Value::try_from(value).unwrap();
-                let string_value = serde_json::to_string(&json_value).unwrap();
-                return builder.body(string_value);
+                let encoding = BodyEncoding::from_accept(http_request.headers().get(ACCEPT).and_then(|a| a.to_str().ok()));
+                builder.content_type(encoding.mime());
+                builder.insert_header((VARY, "Accept"));
+                let body = match encoding.encode(value) {
+                    Ok(body) => body,
+                    Err(err) => return WrapError::from(err).error_response(),
+                };
+                if is_cacheable(&http_request, self.code()) {
+                    let etag = etag_for(&body);
+                    builder.insert_header((ETAG, etag.as_str()));
+                    if if_none_match(&http_request, &etag) {
+                        builder.status(StatusCode::NOT_MODIFIED);
+                        return builder.finish();
+                    }
+                }
+                return builder.body(body);
             }
         }
         builder.finish()
     }
 }
+
+/// Convert a handler response, files in the file storage are looked up first so that a missing
+/// object is answered with 404.
+pub(crate) async fn respond(response: Response, http_request: HttpRequest) -> HttpResponse {
+    if let Some((storage, key)) = stored_file(&response, &http_request) {
+        return match storage.get(&key).await {
+            Ok(Some(object)) => stored_file_response(&http_request, response_builder(&response), &key, object),
+            Ok(None) => WrapError::from(Error::not_found_message_only()).error_response(),
+            Err(err) => WrapError::from(err).error_response(),
+        };
+    }
+    response.into_http_response(http_request)
+}
+
+fn response_builder(response: &Response) -> HttpResponseBuilder {
+    let mut builder = HttpResponse::Ok();
+    builder.status(StatusCode::from_u16(response.code()).unwrap());
+    for key in response.headers().keys() {
+        if key.as_str() == STREAM_HEADER {
+            continue
+        }
+        builder.insert_header((key.clone(), response.headers().get(&key).unwrap().as_str()));
+    }
+    builder
+}
+
+fn stored_file(response: &Response, http_request: &HttpRequest) -> Option<(Arc<dyn FileStorage>, String)> {
+    let file = match response.body().inner.as_ref() {
+        BodyInner::File(file) if !file.is_file() => file,
+        _ => return None,
+    };
+    let storage = http_request.app_data::<&'static ServerOptions>()?.file_storage.as_ref()?;
+    let key = file.to_str().and_then(|location| storage.key_for_location(location))?;
+    Some((storage.clone(), key))
+}
+
+fn is_cacheable(http_request: &HttpRequest, code: u16) -> bool {
+    (http_request.method() == Method::GET || http_request.method() == Method::HEAD) && (200..300).contains(&code)
+}
+
+/// A weak validator, the same data is encoded differently depending on `Accept` and
+/// `Accept-Encoding`.
+fn etag_for(body: &[u8]) -> String {
+    format!("W/\"{}\"", hex_digest(body))
+}
+
+fn hex_digest(data: &[u8]) -> String {
+    digest::digest(&digest::SHA256, data).as_ref()[0..16].iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn if_none_match(http_request: &HttpRequest, etag: &str) -> bool {
+    let opaque_tag = etag.trim_start_matches("W/");
+    http_request.headers().get_all(IF_NONE_MATCH).filter_map(|v| v.to_str().ok()).any(|value| {
+        value.split(',').map(|tag| tag.trim()).any(|tag| tag == "*" || tag.trim_start_matches("W/") == opaque_tag)
+    })
+}
+
+/// A stored file, with `Last-Modified` when the storage knows it. Single byte ranges are
+/// answered with 206, they are cut from the content as it's read from the storage.
+fn stored_file_response(http_request: &HttpRequest, mut builder: HttpResponseBuilder, key: &str, object: StoredObject) -> HttpResponse {
+    // uploads are stored under unique keys, so the key identifies the content
+    let etag = format!("\"{}\"", hex_digest(key.as_bytes()));
+    builder.insert_header((ETAG, etag.as_str()));
+    let last_modified = object.last_modified.map(HttpDate::from);
+    if let Some(last_modified) = &last_modified {
+        builder.insert_header((LAST_MODIFIED, last_modified.to_string()));
+    }
+    if not_modified(http_request, &etag, last_modified.as_ref()) {
+        builder.status(StatusCode::NOT_MODIFIED);
+        return builder.finish();
+    }
+    let mime = Path::new(key).extension().and_then(|e| e.to_str()).map(file_extension_to_mime);
+    if let Some(mime) = mime {
+        builder.content_type(mime.to_string());
+    }
+    let content_length = match object.content_length {
+        Some(content_length) => content_length,
+        None => return builder.streaming(object.content.map(io_error)),
+    };
+    builder.insert_header((ACCEPT_RANGES, "bytes"));
+    match requested_range(http_request, &etag, last_modified.as_ref(), content_length) {
+        Some(Some((start, end))) => {
+            builder.status(StatusCode::PARTIAL_CONTENT);
+            builder.insert_header((CONTENT_RANGE, format!("bytes {}-{}/{}", start, end, content_length)));
+            builder.no_chunking(end - start + 1);
+            builder.streaming(slice_content(object.content, start, end - start + 1).map(io_error))
+        }
+        Some(None) => {
+            builder.status(StatusCode::RANGE_NOT_SATISFIABLE);
+            builder.insert_header((CONTENT_RANGE, format!("bytes */{}", content_length)));
+            builder.finish()
+        }
+        None => {
+            builder.no_chunking(content_length);
+            builder.streaming(object.content.map(io_error))
+        }
+    }
+}
+
+fn io_error(chunk: teo_result::Result<Bytes>) -> std::io::Result<Bytes> {
+    chunk.map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, format!("{}", err)))
+}
+
+/// `If-None-Match` takes precedence over `If-Modified-Since`.
+fn not_modified(http_request: &HttpRequest, etag: &str, last_modified: Option<&HttpDate>) -> bool {
+    if http_request.headers().contains_key(IF_NONE_MATCH) {
+        return if_none_match(http_request, etag);
+    }
+    let since = http_request.headers().get(IF_MODIFIED_SINCE).and_then(|v| v.to_str().ok()).and_then(|v| v.parse::<HttpDate>().ok());
+    match (last_modified, since) {
+        (Some(last_modified), Some(since)) => SystemTime::from(*last_modified) <= SystemTime::from(since),
+        _ => false,
+    }
+}
+
+/// The inclusive bounds of a single byte range in `Range`. `None` when the whole content is
+/// sent, because there's no range, it's malformed, it has several parts or `If-Range` doesn't
+/// match. `Some(None)` when it's not satisfiable.
+fn requested_range(http_request: &HttpRequest, etag: &str, last_modified: Option<&HttpDate>, content_length: u64) -> Option<Option<(u64, u64)>> {
+    let range = http_request.headers().get(RANGE)?.to_str().ok()?.trim().strip_prefix("bytes=")?;
+    if let Some(if_range) = http_request.headers().get(IF_RANGE).and_then(|v| v.to_str().ok()) {
+        let matches = if_range == etag || last_modified.map(|l| l.to_string() == if_range).unwrap_or(false);
+        if !matches {
+            return None;
+        }
+    }
+    if range.contains(',') {
+        return None;
+    }
+    let (start, end) = range.split_once('-')?;
+    let (start, end) = (start.trim(), end.trim());
+    if start.is_empty() {
+        let suffix: u64 = end.parse().ok()?;
+        if suffix == 0 || content_length == 0 {
+            return Some(None);
+        }
+        return Some(Some((content_length - suffix.min(content_length), content_length - 1)));
+    }
+    let start: u64 = start.parse().ok()?;
+    let end: u64 = if end.is_empty() { u64::MAX } else { end.parse().ok()? };
+    if start > end {
+        return None;
+    }
+    if start >= content_length {
+        return Some(None);
+    }
+    Some(Some((start, end.min(content_length - 1))))
+}
+
+/// `length` bytes of `content` from `start`. The chunks before are read and dropped.
+fn slice_content(content: ByteStream, start: u64, length: u64) -> impl Stream<Item = teo_result::Result<Bytes>> {
+    futures_util::stream::unfold((content, start, length), |(mut content, mut skip, remaining)| async move {
+        while remaining > 0 {
+            let chunk = match content.next().await? {
+                Ok(chunk) => chunk,
+                Err(err) => return Some((Err(err), (content, 0, 0))),
+            };
+            if skip >= chunk.len() as u64 {
+                skip -= chunk.len() as u64;
+                continue;
+            }
+            let chunk = chunk.slice(skip as usize..);
+            let taken = remaining.min(chunk.len() as u64);
+            return Some((Ok(chunk.slice(..taken as usize)), (content, 0, remaining - taken)));
+        }
+        None
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+    use actix_web::http::Method;
+    use actix_web::http::header::{HttpDate, IF_MODIFIED_SINCE, IF_NONE_MATCH, IF_RANGE, RANGE};
+    use actix_web::test::TestRequest;
+    use actix_web::web::Bytes;
+    use futures_util::StreamExt;
+    use teo_result::Result;
+    use crate::server::stream::{stream_response, take_stream, STREAM_HEADER};
+    use super::{etag_for, if_none_match, is_cacheable, not_modified, requested_range, slice_content, IntoHttpResponse};
+
+    #[test]
+    fn etags_are_weak_and_stable() {
+        let etag = etag_for(b"{}");
+        assert!(etag.starts_with("W/\""));
+        assert_eq!(etag, etag_for(b"{}"));
+        assert_ne!(etag, etag_for(b"[]"));
+    }
+
+    #[test]
+    fn if_none_match_compares_weakly() {
+        let etag = etag_for(b"{}");
+        let opaque = etag.trim_start_matches("W/").to_owned();
+        assert!(if_none_match(&TestRequest::default().insert_header((IF_NONE_MATCH, etag.as_str())).to_http_request(), &etag));
+        assert!(if_none_match(&TestRequest::default().insert_header((IF_NONE_MATCH, format!("\"other\", {}", opaque))).to_http_request(), &etag));
+        assert!(if_none_match(&TestRequest::default().insert_header((IF_NONE_MATCH, "*")).to_http_request(), &etag));
+        assert!(!if_none_match(&TestRequest::default().insert_header((IF_NONE_MATCH, "\"other\"")).to_http_request(), &etag));
+        assert!(!if_none_match(&TestRequest::default().to_http_request(), &etag));
+    }
+
+    #[test]
+    fn only_successful_reads_are_cacheable() {
+        assert!(is_cacheable(&TestRequest::default().to_http_request(), 200));
+        assert!(is_cacheable(&TestRequest::default().method(Method::HEAD).to_http_request(), 200));
+        assert!(!is_cacheable(&TestRequest::default().method(Method::POST).to_http_request(), 200));
+        assert!(!is_cacheable(&TestRequest::default().to_http_request(), 404));
+    }
+
+    fn range(value: &str, content_length: u64) -> Option<Option<(u64, u64)>> {
+        requested_range(&TestRequest::default().insert_header((RANGE, value)).to_http_request(), "\"tag\"", None, content_length)
+    }
+
+    #[test]
+    fn single_byte_ranges_are_resolved() {
+        assert_eq!(range("bytes=0-9", 100), Some(Some((0, 9))));
+        assert_eq!(range("bytes=90-", 100), Some(Some((90, 99))));
+        assert_eq!(range("bytes=90-200", 100), Some(Some((90, 99))));
+        assert_eq!(range("bytes=-10", 100), Some(Some((90, 99))));
+        assert_eq!(range("bytes=-200", 100), Some(Some((0, 99))));
+        assert_eq!(range("bytes=100-", 100), Some(None));
+        assert_eq!(range("bytes=-0", 100), Some(None));
+        assert_eq!(range("bytes=0-1, 5-6", 100), None);
+        assert_eq!(range("items=0-1", 100), None);
+        assert_eq!(range("bytes=5-1", 100), None);
+    }
+
+    #[test]
+    fn ranges_are_ignored_when_if_range_does_not_match() {
+        let request = |if_range: &str| TestRequest::default().insert_header((RANGE, "bytes=0-1")).insert_header((IF_RANGE, if_range)).to_http_request();
+        assert_eq!(requested_range(&request("\"tag\""), "\"tag\"", None, 10), Some(Some((0, 1))));
+        assert_eq!(requested_range(&request("\"other\""), "\"tag\"", None, 10), None);
+    }
+
+    #[test]
+    fn if_modified_since_is_compared_in_seconds() {
+        let modified = HttpDate::from(SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000));
+        let request = |since: SystemTime| TestRequest::default().insert_header((IF_MODIFIED_SINCE, HttpDate::from(since).to_string())).to_http_request();
+        assert!(not_modified(&request(SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000)), "\"tag\"", Some(&modified)));
+        assert!(!not_modified(&request(SystemTime::UNIX_EPOCH + Duration::from_secs(999_999)), "\"tag\"", Some(&modified)));
+        let request = TestRequest::default().insert_header((IF_NONE_MATCH, "\"other\"")).insert_header((IF_MODIFIED_SINCE, modified.to_string())).to_http_request();
+        assert!(!not_modified(&request, "\"tag\"", Some(&modified)));
+    }
+
+    #[tokio::test]
+    async fn content_is_sliced_across_chunks() {
+        let chunks: Vec<Result<Bytes>> = vec![Ok(Bytes::from_static(b"abc")), Ok(Bytes::from_static(b"def")), Ok(Bytes::from_static(b"ghi"))];
+        let sliced: Vec<Bytes> = slice_content(Box::pin(futures_util::stream::iter(chunks)), 2, 5).map(|chunk| chunk.unwrap()).collect().await;
+        assert_eq!(sliced.concat(), b"cdefg");
+    }
+
+    #[tokio::test]
+    async fn the_stream_header_is_never_sent() {
+        let stream = || futures_util::stream::iter(vec![Ok(Bytes::from_static(b"a"))]);
+        let http_response = stream_response("text/plain", stream()).into_http_response(TestRequest::default().to_http_request());
+        assert!(http_response.headers().get(STREAM_HEADER).is_none());
+        // not even when the stream is gone
+        let response = stream_response("text/plain", stream());
+        take_stream(&response.headers().get(STREAM_HEADER).unwrap());
+        let http_response = response.into_http_response(TestRequest::default().to_http_request());
+        assert!(http_response.headers().get(STREAM_HEADER).is_none());
+    }
+}
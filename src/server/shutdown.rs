@@ -0,0 +1,58 @@
+use std::time::Duration;
+use actix_web::dev::ServerHandle;
+use crate::message::info_message;
+use crate::server::health::set_ready;
+
+/// Stop accepting connections once SIGINT or SIGTERM is received and let the workers drain
+/// in-flight requests until the configured shutdown timeout.
+pub(super) async fn shutdown_on_signal(handles: Vec<ServerHandle>, silent: bool) {
+    wait_for_signal().await;
+    set_ready(false);
+    if !silent {
+        info_message("shutting down, waiting for in-flight requests");
+    }
+    for handle in handles {
+        handle.stop(true).await;
+    }
+}
+
+/// The shutdown timeout in whole seconds as actix expects it, rounded up so that sub-second
+/// timeouts don't become zero.
+pub(super) fn shutdown_timeout_secs(timeout: Duration) -> u64 {
+    timeout.as_secs() + if timeout.subsec_nanos() > 0 { 1 } else { 0 }
+}
+
+#[cfg(unix)]
+async fn wait_for_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(terminate) => terminate,
+        Err(_) => {
+            let _ = tokio::signal::ctrl_c().await;
+            return
+        }
+    };
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => (),
+        _ = terminate.recv() => (),
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_for_signal() {
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use super::shutdown_timeout_secs;
+
+    #[test]
+    fn shutdown_timeout_is_rounded_up() {
+        assert_eq!(shutdown_timeout_secs(Duration::from_secs(30)), 30);
+        assert_eq!(shutdown_timeout_secs(Duration::from_millis(500)), 1);
+        assert_eq!(shutdown_timeout_secs(Duration::from_millis(1500)), 2);
+        assert_eq!(shutdown_timeout_secs(Duration::ZERO), 0);
+    }
+}
@@ -1,15 +1,243 @@
-use std::path::PathBuf;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use actix_files::NamedFile;
+use actix_web::{HttpRequest, HttpResponse};
+use actix_web::http::Method;
+use actix_web::http::header::{ContentEncoding, HeaderValue, ACCEPT, ACCEPT_ENCODING, CACHE_CONTROL, VARY};
 use teo_result::{Result, Error};
 use teo_runtime::response::Response;
 use teo_runtime::error_runtime_ext::ErrorRuntimeExt;
+use crate::server::compression::accept_encoding_quality;
 
 pub fn serve_static_files(base: impl AsRef<str>, path: impl AsRef<str>) -> Result<Response> {
-    let base_str = base.as_ref();
-    let path_str = path.as_ref();
-    let combined_path = PathBuf::from(base_str).join(path_str);
-    if combined_path.is_file() {
-        Ok(Response::file(combined_path))
-    } else {
-        Err(Error::not_found_message_only())
+    match safe_join(Path::new(base.as_ref()), path.as_ref()) {
+        Some(combined_path) if combined_path.is_file() => Ok(Response::file(combined_path)),
+        _ => Err(Error::not_found_message_only()),
+    }
+}
+
+/// A directory served under a URL prefix, before requests are matched against handlers.
+#[derive(Debug, Clone)]
+pub struct StaticMount {
+    pub prefix: String,
+    pub dir: PathBuf,
+    /// Files served for a request to a directory, in order of preference.
+    pub index_files: Vec<String>,
+    /// Serve the first index file of `dir` for browser navigations under `prefix` which match
+    /// neither a file nor a handler, so client side routers can take over.
+    pub spa_fallback: bool,
+    /// `Cache-Control` values keyed by file extension without the dot. `*` applies to other
+    /// extensions.
+    pub cache_control: BTreeMap<String, String>,
+    /// Serve `file.br` or `file.gz` next to `file` to clients accepting those encodings.
+    pub precompressed: bool,
+}
+
+impl StaticMount {
+
+    pub fn new(prefix: impl Into<String>, dir: impl Into<PathBuf>) -> Self {
+        Self {
+            prefix: prefix.into(),
+            dir: dir.into(),
+            index_files: vec!["index.html".to_owned()],
+            spa_fallback: false,
+            cache_control: BTreeMap::new(),
+            precompressed: false,
+        }
+    }
+
+    /// The path relative to `dir` of a request path under `prefix`.
+    fn relative_path<'a>(&self, path: &'a str) -> Option<&'a str> {
+        let prefix = self.prefix.trim_end_matches('/');
+        let rest = path.strip_prefix(prefix)?;
+        if rest.is_empty() || rest.starts_with('/') {
+            Some(rest.trim_start_matches('/'))
+        } else {
+            None
+        }
+    }
+
+    fn cache_control_for(&self, file: &Path) -> Option<&String> {
+        let extension = file.extension().and_then(|e| e.to_str()).unwrap_or("");
+        self.cache_control.get(extension).or_else(|| self.cache_control.get("*"))
+    }
+}
+
+/// Serve a file from the first mount which has it.
+pub(crate) fn serve_static_mounts(mounts: &Vec<StaticMount>, http_request: &HttpRequest) -> Option<HttpResponse> {
+    if !is_get_or_head(http_request) {
+        return None;
+    }
+    for mount in mounts {
+        let relative_path = match mount.relative_path(http_request.path()) {
+            Some(relative_path) => relative_path,
+            None => continue,
+        };
+        let relative_path = match percent_encoding::percent_decode_str(relative_path).decode_utf8() {
+            Ok(relative_path) => relative_path,
+            Err(_) => continue,
+        };
+        let file = match safe_join(&mount.dir, &relative_path) {
+            Some(file) => file,
+            None => continue,
+        };
+        let file = if file.is_dir() {
+            match mount.index_files.iter().map(|index| file.join(index)).find(|index| index.is_file()) {
+                Some(index) => index,
+                None => continue,
+            }
+        } else {
+            file
+        };
+        if file.is_file() {
+            return file_response(mount, &file, http_request);
+        }
+    }
+    None
+}
+
+/// Serve the index file of a mount with `spa_fallback` for a page request nothing else matched.
+pub(crate) fn serve_spa_fallback(mounts: &Vec<StaticMount>, http_request: &HttpRequest) -> Option<HttpResponse> {
+    if !is_get_or_head(http_request) {
+        return None;
+    }
+    // API clients should still receive 404 for missing records and routes
+    let accepts_html = http_request.headers().get(ACCEPT).and_then(|a| a.to_str().ok()).map(|a| a.contains("text/html")).unwrap_or(false);
+    if !accepts_html {
+        return None;
+    }
+    for mount in mounts {
+        if !mount.spa_fallback || mount.relative_path(http_request.path()).is_none() {
+            continue;
+        }
+        if let Some(index) = mount.index_files.iter().map(|index| mount.dir.join(index)).find(|index| index.is_file()) {
+            return file_response(mount, &index, http_request);
+        }
+    }
+    None
+}
+
+fn is_get_or_head(http_request: &HttpRequest) -> bool {
+    http_request.method() == Method::GET || http_request.method() == Method::HEAD
+}
+
+fn file_response(mount: &StaticMount, file: &Path, http_request: &HttpRequest) -> Option<HttpResponse> {
+    let mut named_file = None;
+    if mount.precompressed {
+        let accept_encoding = http_request.headers().get(ACCEPT_ENCODING).and_then(|a| a.to_str().ok()).unwrap_or("");
+        for (extension, encoding) in precompressed_candidates(accept_encoding) {
+            let mut compressed = file.as_os_str().to_owned();
+            compressed.push(".");
+            compressed.push(extension);
+            if let Ok(compressed_file) = NamedFile::open(&compressed) {
+                // the content type is the one of the original file
+                named_file = Some(compressed_file
+                    .set_content_type(actix_files::file_extension_to_mime(file.extension().and_then(|e| e.to_str()).unwrap_or("")))
+                    .set_content_encoding(encoding)
+                    .disable_content_disposition());
+                break;
+            }
+        }
+    }
+    let named_file = match named_file {
+        Some(named_file) => named_file,
+        None => NamedFile::open(file).ok()?,
+    };
+    let mut response = named_file.into_response(http_request);
+    if mount.precompressed {
+        response.headers_mut().append(VARY, HeaderValue::from_static("Accept-Encoding"));
+    }
+    if let Some(cache_control) = mount.cache_control_for(file).and_then(|c| HeaderValue::from_str(c).ok()) {
+        response.headers_mut().insert(CACHE_CONTROL, cache_control);
+    }
+    Some(response)
+}
+
+/// The precompressed variants a client accepts as file extension and encoding, the most
+/// preferred first. Encodings with `q=0` are refused.
+fn precompressed_candidates(accept_encoding: &str) -> Vec<(&'static str, ContentEncoding)> {
+    let mut candidates: Vec<(f32, &'static str, ContentEncoding)> = [("br", "br", ContentEncoding::Brotli), ("gzip", "gz", ContentEncoding::Gzip)]
+        .into_iter()
+        .filter_map(|(token, extension, encoding)| {
+            let quality = accept_encoding_quality(accept_encoding, token).or_else(|| accept_encoding_quality(accept_encoding, "*"))?;
+            (quality > 0.0).then_some((quality, extension, encoding))
+        })
+        .collect();
+    // the sort is stable, so brotli stays first on a tie
+    candidates.sort_by(|a, b| b.0.total_cmp(&a.0));
+    candidates.into_iter().map(|(_, extension, encoding)| (extension, encoding)).collect()
+}
+
+/// Join a decoded request path to `base`. `None` when the path is invalid or leads outside of
+/// `base`, including through symbolic links.
+fn safe_join(base: &Path, path: &str) -> Option<PathBuf> {
+    let mut result = base.to_path_buf();
+    for segment in path.split('/') {
+        match segment {
+            "" | "." => continue,
+            ".." => return None,
+            segment if segment.contains('\\') || segment.contains('\0') => return None,
+            // drive prefixes like `C:` replace the base on Windows
+            segment if cfg!(windows) && segment.contains(':') => return None,
+            segment => result.push(segment),
+        }
+    }
+    if result.exists() {
+        let canonical_base = base.canonicalize().ok()?;
+        if !result.canonicalize().ok()?.starts_with(&canonical_base) {
+            return None;
+        }
+    }
+    Some(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use actix_web::http::header::ContentEncoding;
+    use super::{precompressed_candidates, safe_join};
+
+    #[test]
+    fn paths_stay_inside_the_base() {
+        let base = Path::new("/srv/public");
+        assert_eq!(safe_join(base, "a/b.txt"), Some(base.join("a").join("b.txt")));
+        assert_eq!(safe_join(base, "./a//b.txt"), Some(base.join("a").join("b.txt")));
+        assert_eq!(safe_join(base, "../secret"), None);
+        assert_eq!(safe_join(base, "a/../../secret"), None);
+        assert_eq!(safe_join(base, "a\\..\\secret"), None);
+        assert_eq!(safe_join(base, "a\0b"), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn colons_are_file_names_on_unix() {
+        let base = Path::new("/srv/public");
+        assert_eq!(safe_join(base, "12:00.txt"), Some(base.join("12:00.txt")));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symbolic_links_out_of_the_base_are_refused() {
+        let dir = std::env::temp_dir().join(format!("teo-static-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(dir.join("public")).unwrap();
+        std::fs::write(dir.join("secret"), b"secret").unwrap();
+        std::os::unix::fs::symlink(dir.join("secret"), dir.join("public").join("link")).unwrap();
+        assert_eq!(safe_join(&dir.join("public"), "link"), None);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn paths_are_not_decoded_again() {
+        let base = Path::new("/srv/public");
+        assert_eq!(safe_join(base, "a%2Fb"), Some(base.join("a%2Fb")));
+    }
+
+    #[test]
+    fn precompressed_variants_follow_accept_encoding() {
+        assert_eq!(precompressed_candidates("gzip, br"), vec![("br", ContentEncoding::Brotli), ("gz", ContentEncoding::Gzip)]);
+        assert_eq!(precompressed_candidates("br;q=0.5, gzip"), vec![("gz", ContentEncoding::Gzip), ("br", ContentEncoding::Brotli)]);
+        assert_eq!(precompressed_candidates("br;q=0, gzip"), vec![("gz", ContentEncoding::Gzip)]);
+        assert_eq!(precompressed_candidates("*;q=0"), vec![]);
+        assert_eq!(precompressed_candidates(""), vec![]);
     }
 }
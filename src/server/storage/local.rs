@@ -0,0 +1,129 @@
+use std::path::PathBuf;
+use actix_web::web::{self, Bytes};
+use async_trait::async_trait;
+use futures_util::{StreamExt, TryStreamExt};
+use teo_result::{Error, Result};
+use tokio::io::AsyncWriteExt;
+use tokio_util::io::ReaderStream;
+use crate::server::storage::{ByteStream, FileStorage, StoredObject};
+
+/// Keep files in a directory on the local file system. Locations are plain file paths, so
+/// handlers can open stored files directly.
+#[derive(Debug, Clone)]
+pub struct LocalStorage {
+    root: PathBuf,
+}
+
+impl LocalStorage {
+
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.root.join(key)
+    }
+}
+
+#[async_trait]
+impl FileStorage for LocalStorage {
+
+    async fn put(&self, key: &str, _content_type: Option<&str>, content: Bytes) -> Result<()> {
+        let path = self.path(key);
+        web::block(move || {
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::write(path, content)
+        }).await.map_err(|e| Error::new(format!("{}", e)))?.map_err(|e| Error::new(format!("{}", e)))
+    }
+
+    async fn put_stream(&self, key: &str, _content_type: Option<&str>, mut content: ByteStream) -> Result<()> {
+        let destination = self.path(key);
+        if let Some(parent) = destination.parent() {
+            tokio::fs::create_dir_all(parent).await.map_err(|e| Error::new(format!("{}", e)))?;
+        }
+        // the content is written next to its destination, a failed upload leaves no file behind
+        let partial = destination.with_file_name(format!(".{}.partial", uuid::Uuid::new_v4()));
+        let result = async {
+            let mut file = tokio::fs::File::create(&partial).await.map_err(|e| Error::new(format!("{}", e)))?;
+            while let Some(chunk) = content.next().await {
+                file.write_all(&chunk?).await.map_err(|e| Error::new(format!("{}", e)))?;
+            }
+            file.flush().await.map_err(|e| Error::new(format!("{}", e)))?;
+            tokio::fs::rename(&partial, &destination).await.map_err(|e| Error::new(format!("{}", e)))
+        }.await;
+        if result.is_err() {
+            let _ = tokio::fs::remove_file(&partial).await;
+        }
+        result
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<StoredObject>> {
+        let file = match tokio::fs::File::open(self.path(key)).await {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => Err(Error::new(format!("{}", e)))?,
+        };
+        let metadata = file.metadata().await.map_err(|e| Error::new(format!("{}", e)))?;
+        if !metadata.is_file() {
+            return Ok(None);
+        }
+        Ok(Some(StoredObject {
+            content_length: Some(metadata.len()),
+            last_modified: metadata.modified().ok(),
+            content: Box::pin(ReaderStream::new(file).map_err(|e| Error::new(format!("{}", e)))),
+        }))
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        let path = self.path(key);
+        web::block(move || if path.is_file() {
+            std::fs::remove_file(path)
+        } else {
+            Ok(())
+        }).await.map_err(|e| Error::new(format!("{}", e)))?.map_err(|e| Error::new(format!("{}", e)))
+    }
+
+    fn location(&self, key: &str) -> String {
+        self.path(key).to_string_lossy().to_string()
+    }
+
+    fn key_for_location(&self, location: &str) -> Option<String> {
+        PathBuf::from(location).strip_prefix(&self.root).ok().map(|key| key.to_string_lossy().to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::web::Bytes;
+    use teo_result::{Error, Result};
+    use crate::server::storage::FileStorage;
+    use super::LocalStorage;
+
+    #[tokio::test]
+    async fn streams_are_written_into_the_storage() {
+        let dir = std::env::temp_dir().join(format!("teo-local-storage-{}", uuid::Uuid::new_v4()));
+        let storage = LocalStorage::new(dir.clone());
+        let chunks: Vec<Result<Bytes>> = vec![Ok(Bytes::from_static(b"con")), Ok(Bytes::from_static(b"tent"))];
+        storage.put_stream("a/b.txt", None, Box::pin(futures_util::stream::iter(chunks))).await.unwrap();
+        let object = storage.get("a/b.txt").await.unwrap().unwrap();
+        assert_eq!(object.content_length, Some(7));
+        assert!(object.last_modified.is_some());
+        assert_eq!(object.bytes().await.unwrap().as_ref(), b"content");
+        assert_eq!(storage.key_for_location(&storage.location("a/b.txt")).as_deref(), Some("a/b.txt"));
+        storage.delete("a/b.txt").await.unwrap();
+        assert!(storage.get("a/b.txt").await.unwrap().is_none());
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn failed_streams_leave_nothing_behind() {
+        let dir = std::env::temp_dir().join(format!("teo-local-storage-{}", uuid::Uuid::new_v4()));
+        let storage = LocalStorage::new(dir.clone());
+        let chunks: Vec<Result<Bytes>> = vec![Ok(Bytes::from_static(b"con")), Err(Error::new("too large"))];
+        assert!(storage.put_stream("b.txt", None, Box::pin(futures_util::stream::iter(chunks))).await.is_err());
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
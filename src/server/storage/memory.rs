@@ -0,0 +1,46 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::SystemTime;
+use actix_web::web::Bytes;
+use async_trait::async_trait;
+use teo_result::Result;
+use crate::server::storage::{FileStorage, StoredObject};
+
+/// Keep files in memory. Useful for tests and for development servers.
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    files: Mutex<HashMap<String, (Bytes, SystemTime)>>,
+}
+
+impl MemoryStorage {
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl FileStorage for MemoryStorage {
+
+    async fn put(&self, key: &str, _content_type: Option<&str>, content: Bytes) -> Result<()> {
+        self.files.lock().unwrap().insert(key.to_owned(), (content, SystemTime::now()));
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<StoredObject>> {
+        Ok(self.files.lock().unwrap().get(key).map(|(content, modified)| StoredObject::from_bytes(content.clone(), Some(*modified))))
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        self.files.lock().unwrap().remove(key);
+        Ok(())
+    }
+
+    fn location(&self, key: &str) -> String {
+        format!("memory://{}", key)
+    }
+
+    fn key_for_location(&self, location: &str) -> Option<String> {
+        location.strip_prefix("memory://").map(|key| key.to_owned())
+    }
+}
@@ -0,0 +1,74 @@
+pub mod local;
+pub mod memory;
+pub mod s3;
+
+use std::fmt::Debug;
+use std::pin::Pin;
+use std::time::SystemTime;
+use actix_web::web::{Bytes, BytesMut};
+use async_trait::async_trait;
+use futures_util::{Stream, StreamExt};
+use teo_result::Result;
+
+pub use local::LocalStorage;
+pub use memory::MemoryStorage;
+pub use s3::S3Storage;
+
+/// A place where uploaded files are kept. Handlers receive the `location` of a stored file as
+/// its `filepath`, and `Response::file` with such a location is served from the storage.
+#[async_trait]
+pub trait FileStorage: Debug + Send + Sync {
+
+    async fn put(&self, key: &str, content_type: Option<&str>, content: Bytes) -> Result<()>;
+
+    /// Store the chunks of `content` as they are received, failing with the error of `content`
+    /// when it fails. Uploads are stored this way, without being written anywhere else first.
+    /// The default collects the content in memory, override it for large files.
+    async fn put_stream(&self, key: &str, content_type: Option<&str>, mut content: ByteStream) -> Result<()> {
+        let mut buffer = BytesMut::new();
+        while let Some(chunk) = content.next().await {
+            buffer.extend_from_slice(&chunk?);
+        }
+        self.put(key, content_type, buffer.freeze()).await
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<StoredObject>>;
+
+    async fn delete(&self, key: &str) -> Result<()>;
+
+    /// The `filepath` handed to handlers for `key`.
+    fn location(&self, key: &str) -> String;
+
+    /// The key of a location produced by `location`, `None` if it isn't from this storage.
+    fn key_for_location(&self, location: &str) -> Option<String>;
+}
+
+pub type ByteStream = Pin<Box<dyn Stream<Item = Result<Bytes>> + Send>>;
+
+/// An object read from a storage. The content is streamed, so large objects are not held in
+/// memory.
+pub struct StoredObject {
+    pub content_length: Option<u64>,
+    pub last_modified: Option<SystemTime>,
+    pub content: ByteStream,
+}
+
+impl StoredObject {
+
+    pub fn from_bytes(content: Bytes, last_modified: Option<SystemTime>) -> Self {
+        Self {
+            content_length: Some(content.len() as u64),
+            last_modified,
+            content: Box::pin(futures_util::stream::once(async move { Ok(content) })),
+        }
+    }
+
+    /// Read the whole content into memory.
+    pub async fn bytes(mut self) -> Result<Bytes> {
+        let mut buffer = BytesMut::new();
+        while let Some(chunk) = self.content.next().await {
+            buffer.extend_from_slice(&chunk?);
+        }
+        Ok(buffer.freeze())
+    }
+}
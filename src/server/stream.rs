@@ -0,0 +1,183 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use actix_web::web::Bytes;
+use futures_util::{Stream, StreamExt};
+use once_cell::sync::Lazy;
+use teo_result::Result;
+use teo_runtime::response::Response;
+use uuid::Uuid;
+use crate::server::request_id::{current_request_id, own_stream};
+
+pub type BodyStream = Pin<Box<dyn Stream<Item = Result<Bytes>> + Send>>;
+
+/// The header which links a response to its registered stream. It's never sent to clients,
+/// every response is built by `response_builder` which skips it.
+pub(crate) const STREAM_HEADER: &str = "x-teo-stream";
+
+/// How long a stream registered outside of a request is kept for a response to take it.
+const ABANDONED_AFTER: Duration = Duration::from_secs(60);
+
+struct RegisteredStream {
+    stream: BodyStream,
+    /// When it was registered, for streams which no request owns.
+    unowned_since: Option<Instant>,
+}
+
+static STREAMS: Lazy<Mutex<HashMap<String, RegisteredStream>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// A response whose body is sent with chunked transfer encoding as `stream` yields. The stream
+/// is released when the request ends without sending the response. Responses created outside
+/// of a request, like in a spawned task, have a minute to be sent.
+pub fn stream_response<S>(content_type: &str, stream: S) -> Response where S: Stream<Item = Result<Bytes>> + Send + 'static {
+    let id = Uuid::new_v4().to_string();
+    let now = Instant::now();
+    let unowned_since = if current_request_id().is_some() { None } else { Some(now) };
+    {
+        let mut streams = STREAMS.lock().unwrap();
+        streams.retain(|_, registered| registered.unowned_since.map_or(true, |since| now.duration_since(since) < ABANDONED_AFTER));
+        streams.insert(id.clone(), RegisteredStream { stream: Box::pin(stream), unowned_since });
+    }
+    own_stream(id.clone());
+    let response = Response::empty();
+    response.headers().set("content-type", content_type);
+    response.headers().set(STREAM_HEADER, id);
+    response
+}
+
+pub(crate) fn take_stream(id: &str) -> Option<BodyStream> {
+    STREAMS.lock().unwrap().remove(id).map(|registered| registered.stream)
+}
+
+/// The streams registered while handling a request. Streams whose response was dropped
+/// instead of being sent are removed when the request ends.
+#[derive(Debug, Default)]
+pub(crate) struct StreamOwner(RefCell<Vec<String>>);
+
+impl StreamOwner {
+
+    pub(crate) fn own(&self, id: String) {
+        self.0.borrow_mut().push(id);
+    }
+}
+
+impl Drop for StreamOwner {
+
+    fn drop(&mut self) {
+        let ids = self.0.get_mut();
+        if ids.is_empty() {
+            return;
+        }
+        let mut streams = STREAMS.lock().unwrap();
+        for id in ids.iter() {
+            streams.remove(id);
+        }
+    }
+}
+
+/// An event of a `text/event-stream` response.
+#[derive(Debug, Clone, Default)]
+pub struct SseEvent {
+    pub id: Option<String>,
+    pub event: Option<String>,
+    pub data: String,
+    pub retry: Option<Duration>,
+}
+
+impl SseEvent {
+
+    pub fn new(data: impl Into<String>) -> Self {
+        Self { data: data.into(), ..Default::default() }
+    }
+
+    pub fn id(mut self, id: impl Into<String>) -> Self {
+        self.id = Some(id.into());
+        self
+    }
+
+    pub fn event(mut self, event: impl Into<String>) -> Self {
+        self.event = Some(event.into());
+        self
+    }
+
+    pub fn retry(mut self, retry: Duration) -> Self {
+        self.retry = Some(retry);
+        self
+    }
+
+    fn to_bytes(&self) -> Bytes {
+        let mut result = String::new();
+        if let Some(id) = &self.id {
+            result.push_str(&format!("id: {}\n", id));
+        }
+        if let Some(event) = &self.event {
+            result.push_str(&format!("event: {}\n", event));
+        }
+        if let Some(retry) = &self.retry {
+            result.push_str(&format!("retry: {}\n", retry.as_millis()));
+        }
+        for line in self.data.split('\n') {
+            result.push_str(&format!("data: {}\n", line));
+        }
+        result.push('\n');
+        Bytes::from(result)
+    }
+}
+
+/// A Server-Sent Events response. A keep-alive comment is written whenever no event has been
+/// sent for `keep_alive`, so proxies don't close idle connections.
+pub fn sse_response<S>(events: S, keep_alive: Option<Duration>) -> Response where S: Stream<Item = Result<SseEvent>> + Send + 'static {
+    let events = events.map(|event| event.map(|event| event.to_bytes())).boxed();
+    let stream: BodyStream = match keep_alive {
+        Some(keep_alive) => Box::pin(futures_util::stream::unfold(events, move |mut events| async move {
+            match tokio::time::timeout(keep_alive, events.next()).await {
+                Ok(Some(item)) => Some((item, events)),
+                Ok(None) => None,
+                Err(_) => Some((Ok(Bytes::from_static(b": keep-alive\n\n")), events)),
+            }
+        })),
+        None => events,
+    };
+    let response = stream_response("text/event-stream", stream);
+    response.headers().set("cache-control", "no-cache");
+    response.headers().set("x-accel-buffering", "no");
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+    use actix_web::web::Bytes;
+    use crate::server::request_id::with_request_id;
+    use super::{stream_response, take_stream, ABANDONED_AFTER, STREAMS, STREAM_HEADER};
+
+    fn response_stream_id() -> String {
+        let response = stream_response("text/plain", futures_util::stream::iter(vec![Ok(Bytes::from_static(b"a"))]));
+        let id = response.headers().get(STREAM_HEADER).unwrap();
+        id
+    }
+
+    #[tokio::test]
+    async fn dropped_responses_release_their_streams() {
+        let id = with_request_id("a".to_owned(), async { response_stream_id() }).await;
+        assert!(!STREAMS.lock().unwrap().contains_key(&id));
+    }
+
+    #[tokio::test]
+    async fn streams_outside_of_requests_are_swept() {
+        let id = response_stream_id();
+        assert!(STREAMS.lock().unwrap().contains_key(&id));
+        STREAMS.lock().unwrap().get_mut(&id).unwrap().unowned_since = Some(Instant::now() - ABANDONED_AFTER);
+        let other = response_stream_id();
+        assert!(!STREAMS.lock().unwrap().contains_key(&id));
+        assert!(take_stream(&other).is_some());
+    }
+
+    #[tokio::test]
+    async fn sent_responses_take_their_streams() {
+        let taken = with_request_id("b".to_owned(), async { take_stream(&response_stream_id()).is_some() }).await;
+        assert!(taken);
+    }
+}
@@ -0,0 +1,437 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use async_trait::async_trait;
+use futures_util::{Stream, StreamExt};
+use once_cell::sync::Lazy;
+use serde_json::{json, Map, Value as JsonValue};
+use teo_result::{Error, Result};
+use teo_runtime::error_runtime_ext::ErrorRuntimeExt;
+use teo_runtime::action::action::CREATE;
+use teo_runtime::arguments::Arguments;
+use teo_runtime::handler::handler::Method;
+use teo_runtime::namespace::Namespace;
+use teo_runtime::pipeline;
+use teo_runtime::pipeline::item::BoundedItem;
+use teo_runtime::request;
+use teo_runtime::response::body::BodyInner;
+use teo_teon::Value;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use crate::app::Ctx;
+use crate::server::websocket::{WebSocketConnection, WebSocketHandler};
+
+const CHANNEL_CAPACITY: usize = 1024;
+
+static CHANGES: Lazy<broadcast::Sender<ModelChange>> = Lazy::new(|| broadcast::channel(CHANNEL_CAPACITY).0);
+
+tokio::task_local! {
+    static HELD_CHANGES: RefCell<Vec<ModelChange>>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ModelChangeKind {
+    Create,
+    Update,
+    Delete,
+}
+
+impl ModelChangeKind {
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ModelChangeKind::Create => "create",
+            ModelChangeKind::Update => "update",
+            ModelChangeKind::Delete => "delete",
+        }
+    }
+}
+
+/// A record which was created, updated or deleted. `model` is the dot-joined model path,
+/// like `User` or `admin.User`.
+#[derive(Debug, Clone)]
+pub struct ModelChange {
+    pub model: String,
+    pub kind: ModelChangeKind,
+    pub record: JsonValue,
+}
+
+/// Notify subscribers of a change. Every saved and deleted object is published by the
+/// callbacks `observe_model_changes` adds to the models, whether it's saved by a builtin action
+/// or by a custom handler. Changes made while handling a request are held until the request
+/// succeeds and dropped when it fails, so rolled back changes are not published.
+pub fn publish_model_change(model: &str, kind: ModelChangeKind, record: &Value) -> Result<()> {
+    let record = JsonValue::try_from(record)?;
+    let mut change = Some(ModelChange { model: model.to_owned(), kind, record });
+    let _ = HELD_CHANGES.try_with(|held| held.borrow_mut().push(change.take().unwrap()));
+    if let Some(change) = change {
+        // sending only fails when nobody is subscribed
+        let _ = CHANGES.send(change);
+    }
+    Ok(())
+}
+
+/// Run `future` holding the changes it publishes, they are returned instead of being sent.
+pub(crate) async fn hold_changes<F>(future: F) -> (F::Output, Vec<ModelChange>) where F: Future {
+    HELD_CHANGES.scope(RefCell::new(vec![]), async move {
+        let output = future.await;
+        (output, HELD_CHANGES.with(|held| held.take()))
+    }).await
+}
+
+/// Send held changes, after the response is successful and the transaction is committed.
+pub(crate) fn publish_changes(changes: Vec<ModelChange>) {
+    for change in changes {
+        let _ = CHANGES.send(change);
+    }
+}
+
+/// Append a callback which publishes the change to the `afterSave` and `afterDelete` callbacks
+/// of every model in `namespace` and its child namespaces. Objects which are created, upserts
+/// which create included, are published as `create`, other saved objects as `update`.
+pub(crate) fn observe_model_changes(namespace: &mut Namespace) {
+    for model in namespace.models.values_mut() {
+        model.after_save.items.push(publish_item(false));
+        model.after_delete.items.push(publish_item(true));
+    }
+    for namespace in namespace.namespaces.values_mut() {
+        observe_model_changes(namespace);
+    }
+}
+
+fn publish_item(deleted: bool) -> BoundedItem {
+    BoundedItem {
+        path: vec!["publishModelChange".to_owned()],
+        arguments: Arguments::default(),
+        call: Arc::new(move |_arguments: Arguments, ctx: pipeline::Ctx| async move {
+            let object = ctx.object();
+            let kind = if deleted {
+                ModelChangeKind::Delete
+            } else if object.action().contains(CREATE) {
+                ModelChangeKind::Create
+            } else {
+                ModelChangeKind::Update
+            };
+            let record = object.to_teon().await?;
+            publish_model_change(&object.model().path.join("."), kind, &record)?;
+            Ok(ctx.value().clone())
+        }),
+    }
+}
+
+/// Changes of `model` whose records match a `findMany`-style `where`. The filter is checked
+/// against the record after the change, relation filters are not supported. Records are sent
+/// as saved, read rules are not applied, so don't forward them to clients who may not read them.
+pub fn model_changes(model: &str, filter: JsonValue) -> Result<impl Stream<Item = ModelChange>> {
+    validate_filter(&filter)?;
+    let model = model.to_owned();
+    let receiver = CHANGES.subscribe();
+    Ok(futures_util::stream::unfold(receiver, |mut receiver| async move {
+        loop {
+            match receiver.recv().await {
+                Ok(change) => return Some((change, receiver)),
+                // slow subscribers skip the changes they missed
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    }).filter(move |change| {
+        let matched = change.model == model && record_matches(&change.record, &filter);
+        async move { matched }
+    }))
+}
+
+/// A WebSocket endpoint for live queries. Clients send
+/// `{"subscribe": {"id": "a", "model": "User", "where": {...}}}` and receive
+/// `{"id": "a", "event": "create", "data": {...}}` for each matching change until they send
+/// `{"unsubscribe": "a"}`.
+///
+/// Created and updated records are read again with `findUnique` as the subscriber, through the
+/// namespace middlewares with the handshake request, so clients only receive what they could
+/// fetch themselves. This costs one `findUnique` for each change of a subscribed model on every
+/// connection. Deleted records can't be read, their events only contain the primary key and
+/// subscriptions filtering on other fields don't receive them.
+#[derive(Default)]
+pub struct SubscriptionHandler {
+    subscriptions: Arc<Mutex<HashMap<String, HashMap<String, Subscription>>>>,
+}
+
+struct Subscription {
+    model: String,
+    filter: JsonValue,
+}
+
+impl SubscriptionHandler {
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait(?Send)]
+impl WebSocketHandler for SubscriptionHandler {
+
+    async fn on_open(&self, connection: &WebSocketConnection) -> Result<()> {
+        self.subscriptions.lock().unwrap().insert(connection.id().to_owned(), HashMap::new());
+        let subscriptions = self.subscriptions.clone();
+        let connection = connection.clone();
+        let mut receiver = CHANGES.subscribe();
+        actix_web::rt::spawn(async move {
+            loop {
+                let change = match receiver.recv().await {
+                    Ok(change) => change,
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => break,
+                };
+                let subscribed = match subscriptions.lock().unwrap().get(connection.id()) {
+                    Some(subscriptions) => subscriptions.values().any(|s| s.model == change.model),
+                    // the connection is closed
+                    None => break,
+                };
+                if !subscribed {
+                    continue;
+                }
+                // deleted records are only matched on their primary key, which is all their
+                // events reveal
+                let data = match change.kind {
+                    ModelChangeKind::Delete => primary_key(&change.model, &change.record),
+                    _ => read_as_subscriber(&connection, &change.model, &change.record).await,
+                };
+                let data = match data {
+                    Some(data) => data,
+                    None => continue,
+                };
+                let messages = match subscriptions.lock().unwrap().get(connection.id()) {
+                    Some(subscriptions) => subscriptions.iter()
+                        .filter(|(_, s)| s.model == change.model && record_matches(&data, &s.filter))
+                        .map(|(id, _)| json!({ "id": id, "event": change.kind.as_str(), "data": data }))
+                        .collect::<Vec<JsonValue>>(),
+                    None => break,
+                };
+                for message in messages {
+                    if connection.send_json(&message).await.is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+        Ok(())
+    }
+
+    async fn on_message(&self, connection: &WebSocketConnection, message: Value) -> Result<()> {
+        let message = JsonValue::try_from(&message)?;
+        if let Some(subscribe) = message.get("subscribe") {
+            let id = subscribe.get("id").and_then(|id| id.as_str()).ok_or_else(|| Error::value_error_message_only("subscription id is missing"))?;
+            let model = subscribe.get("model").and_then(|m| m.as_str()).ok_or_else(|| Error::value_error_message_only("subscription model is missing"))?;
+            let filter = subscribe.get("where").cloned().unwrap_or(JsonValue::Object(Map::new()));
+            validate_filter(&filter)?;
+            if let Some(subscriptions) = self.subscriptions.lock().unwrap().get_mut(connection.id()) {
+                subscriptions.insert(id.to_owned(), Subscription { model: model.to_owned(), filter });
+            }
+            connection.send_json(&json!({ "subscribed": id })).await
+        } else if let Some(id) = message.get("unsubscribe").and_then(|id| id.as_str()) {
+            if let Some(subscriptions) = self.subscriptions.lock().unwrap().get_mut(connection.id()) {
+                subscriptions.remove(id);
+            }
+            connection.send_json(&json!({ "unsubscribed": id })).await
+        } else {
+            Err(Error::value_error_message_only("expect `subscribe` or `unsubscribe`"))
+        }
+    }
+
+    async fn on_close(&self, connection: &WebSocketConnection) {
+        self.subscriptions.lock().unwrap().remove(connection.id());
+    }
+}
+
+/// The primary key fields of `record` as a unique `where`.
+fn primary_key(model_path: &str, record: &JsonValue) -> Option<JsonValue> {
+    let model_path: Vec<&str> = model_path.split('.').collect();
+    let model = Ctx::main_namespace().model_at_path(&model_path)?;
+    let keys = model.primary_index()?.keys();
+    let unique = keys.iter().map(|key| Some((key.clone(), record.get(key)?.clone()))).collect::<Option<Map<String, JsonValue>>>()?;
+    Some(JsonValue::Object(unique))
+}
+
+/// Read `record` with `findUnique` as the subscriber of `connection`. `None` when the
+/// subscriber may not read it.
+async fn read_as_subscriber(connection: &WebSocketConnection, model_path: &str, record: &JsonValue) -> Option<JsonValue> {
+    let main_namespace = Ctx::main_namespace();
+    let unique = primary_key(model_path, record)?;
+    let model_path: Vec<String> = model_path.split('.').map(|s| s.to_owned()).collect();
+    let model = main_namespace.model_at_path(&model_path.iter().map(|s| s.as_str()).collect())?;
+    let action = Ctx::server_options().model_actions.get(&model_path, "findUnique")?;
+    let namespace = main_namespace.namespace_at_path(&model_path[0..model_path.len() - 1].to_vec())?;
+    let handler_match = main_namespace.handler_map.default_match(Method::Post, &format!("/{}/findUnique", model_path.join("/")))?;
+    let body = action.transform_input(model, &json!({ "where": unique }), main_namespace).ok()?;
+    let ctx = request::Ctx::new(
+        connection.request().clone(),
+        Arc::new(body),
+        connection.transaction_ctx(),
+        handler_match,
+    );
+    let response = action.call(namespace, ctx, None).await.ok()?;
+    if response.code() >= 300 {
+        return None;
+    }
+    match response.body().inner.as_ref() {
+        BodyInner::Teon(Value::Dictionary(map)) => map.get("data").filter(|data| !data.is_null()).and_then(|data| JsonValue::try_from(data).ok()),
+        _ => None,
+    }
+}
+
+const FIELD_OPERATORS: [&str; 12] = ["equals", "not", "in", "notIn", "lt", "lte", "gt", "gte", "contains", "startsWith", "endsWith", "mode"];
+
+fn validate_filter(filter: &JsonValue) -> Result<()> {
+    let object = filter.as_object().ok_or_else(|| Error::value_error_message_only("where should be an object"))?;
+    for (key, value) in object {
+        match key.as_str() {
+            "AND" | "OR" | "NOT" => match value {
+                JsonValue::Array(filters) => for filter in filters {
+                    validate_filter(filter)?;
+                },
+                filter => validate_filter(filter)?,
+            },
+            _ => validate_field_filter(key, value)?,
+        }
+    }
+    Ok(())
+}
+
+fn validate_field_filter(key: &str, filter: &JsonValue) -> Result<()> {
+    if let JsonValue::Object(operators) = filter {
+        for (operator, operand) in operators {
+            if !FIELD_OPERATORS.contains(&operator.as_str()) {
+                Err(Error::value_error_message_only(format!("filter `{}` of `{}` is not supported in subscriptions", operator, key)))?
+            }
+            if operator == "not" {
+                validate_field_filter(key, operand)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn record_matches(record: &JsonValue, filter: &JsonValue) -> bool {
+    let object = match filter.as_object() {
+        Some(object) => object,
+        None => return false,
+    };
+    object.iter().all(|(key, value)| match key.as_str() {
+        "AND" => filters(value).iter().all(|f| record_matches(record, f)),
+        "OR" => filters(value).iter().any(|f| record_matches(record, f)),
+        "NOT" => !filters(value).iter().any(|f| record_matches(record, f)),
+        _ => field_matches(record.get(key).unwrap_or(&JsonValue::Null), value),
+    })
+}
+
+fn filters(value: &JsonValue) -> Vec<&JsonValue> {
+    match value {
+        JsonValue::Array(filters) => filters.iter().collect(),
+        filter => vec![filter],
+    }
+}
+
+fn field_matches(field: &JsonValue, condition: &JsonValue) -> bool {
+    let operators = match condition {
+        JsonValue::Object(operators) => operators,
+        value => return values_equal(field, value),
+    };
+    let insensitive = operators.get("mode").and_then(|m| m.as_str()) == Some("insensitive");
+    operators.iter().all(|(operator, operand)| match operator.as_str() {
+        "equals" => if insensitive {
+            string_op(field, operand, true, |f, o| f == o)
+        } else {
+            values_equal(field, operand)
+        },
+        "not" => !field_matches(field, operand),
+        "in" => operand.as_array().map(|a| a.iter().any(|o| values_equal(field, o))).unwrap_or(false),
+        "notIn" => operand.as_array().map(|a| !a.iter().any(|o| values_equal(field, o))).unwrap_or(false),
+        "lt" => compare(field, operand).map(|o| o.is_lt()).unwrap_or(false),
+        "lte" => compare(field, operand).map(|o| o.is_le()).unwrap_or(false),
+        "gt" => compare(field, operand).map(|o| o.is_gt()).unwrap_or(false),
+        "gte" => compare(field, operand).map(|o| o.is_ge()).unwrap_or(false),
+        "contains" => string_op(field, operand, insensitive, |f, o| f.contains(o)),
+        "startsWith" => string_op(field, operand, insensitive, |f, o| f.starts_with(o)),
+        "endsWith" => string_op(field, operand, insensitive, |f, o| f.ends_with(o)),
+        "mode" => true,
+        _ => false,
+    })
+}
+
+fn values_equal(a: &JsonValue, b: &JsonValue) -> bool {
+    match (a.as_f64(), b.as_f64()) {
+        (Some(a), Some(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+fn compare(a: &JsonValue, b: &JsonValue) -> Option<std::cmp::Ordering> {
+    match (a, b) {
+        (JsonValue::Number(a), JsonValue::Number(b)) => a.as_f64()?.partial_cmp(&b.as_f64()?),
+        // dates and datetimes are compared by their ISO 8601 representation
+        (JsonValue::String(a), JsonValue::String(b)) => Some(a.cmp(b)),
+        _ => None,
+    }
+}
+
+fn string_op(field: &JsonValue, operand: &JsonValue, insensitive: bool, op: impl Fn(&str, &str) -> bool) -> bool {
+    match (field.as_str(), operand.as_str()) {
+        (Some(f), Some(o)) => if insensitive {
+            op(&f.to_lowercase(), &o.to_lowercase())
+        } else {
+            op(f, o)
+        },
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use teo_teon::Value;
+    use super::{hold_changes, publish_changes, publish_model_change, record_matches, validate_filter, ModelChangeKind, CHANGES};
+
+    #[test]
+    fn field_conditions() {
+        let record = json!({ "name": "Alice", "age": 30, "email": null });
+        assert!(record_matches(&record, &json!({})));
+        assert!(record_matches(&record, &json!({ "name": "Alice" })));
+        assert!(!record_matches(&record, &json!({ "name": "Bob" })));
+        assert!(record_matches(&record, &json!({ "age": { "gte": 30, "lt": 31 } })));
+        assert!(record_matches(&record, &json!({ "age": { "in": [1, 30] } })));
+        assert!(record_matches(&record, &json!({ "name": { "startsWith": "al", "mode": "insensitive" } })));
+        assert!(!record_matches(&record, &json!({ "name": { "contains": "li", "not": { "equals": "Alice" } } })));
+        assert!(record_matches(&record, &json!({ "email": null })));
+    }
+
+    #[test]
+    fn logical_conditions() {
+        let record = json!({ "name": "Alice", "age": 30 });
+        assert!(record_matches(&record, &json!({ "OR": [{ "name": "Bob" }, { "age": 30 }] })));
+        assert!(!record_matches(&record, &json!({ "AND": [{ "name": "Alice" }, { "age": 31 }] })));
+        assert!(!record_matches(&record, &json!({ "NOT": { "name": "Alice" } })));
+    }
+
+    #[test]
+    fn unsupported_filters_are_rejected() {
+        assert!(validate_filter(&json!({ "posts": { "some": {} } })).is_err());
+        assert!(validate_filter(&json!([])).is_err());
+        assert!(validate_filter(&json!({ "OR": [{ "age": { "gt": 1 } }] })).is_ok());
+    }
+
+    #[tokio::test]
+    async fn changes_are_held_until_they_are_published() {
+        let mut receiver = CHANGES.subscribe();
+        let (_, changes) = hold_changes(async {
+            publish_model_change("Held", ModelChangeKind::Update, &Value::Null).unwrap();
+        }).await;
+        assert!(receiver.try_recv().is_err());
+        assert_eq!(changes.len(), 1);
+        publish_changes(changes);
+        let change = receiver.try_recv().unwrap();
+        assert_eq!(change.model, "Held");
+        assert_eq!(change.kind, ModelChangeKind::Update);
+    }
+}
@@ -0,0 +1,235 @@
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+use actix_http::header::HeaderMap;
+use futures_util::future::BoxFuture;
+use opentelemetry::{global, Context, KeyValue};
+use opentelemetry::propagation::Extractor;
+use opentelemetry::trace::{TraceError, TracerProvider as _};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::export::trace::{ExportResult, SpanData, SpanExporter};
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::{self, Sampler, TracerProvider};
+use opentelemetry_sdk::{runtime, Resource};
+use serde_json::json;
+use teo_result::{Error, Result};
+use tracing::{field, info_span, Span};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+
+/// Where finished spans are sent.
+#[derive(Debug, Clone)]
+pub enum TraceExporter {
+    /// An OTLP collector over gRPC, like `http://localhost:4317`.
+    Otlp(String),
+    /// One JSON object per span, with the field names of OTLP's JSON encoding.
+    File(PathBuf),
+}
+
+/// Tracing of requests through body parsing, input validation, the middlewares and the handler.
+/// The handler span is a sibling of the middlewares span rather than its child, the middlewares
+/// span still lasts until the handler returns since middlewares wrap it. Queries run inside the
+/// runtime's connectors and are part of the handler span, they don't have spans of their own. Connecting to databases at startup does. Incoming W3C `traceparent`
+/// headers are continued. It's disabled by default, set `enabled` to record traces.
+#[derive(Debug, Clone)]
+pub struct Telemetry {
+    pub enabled: bool,
+    pub exporter: TraceExporter,
+    pub service_name: String,
+    /// The ratio of traces which are recorded when the caller didn't decide.
+    pub sample_ratio: f64,
+}
+
+impl Default for Telemetry {
+
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            exporter: TraceExporter::Otlp("http://localhost:4317".to_owned()),
+            service_name: "teo".to_owned(),
+            sample_ratio: 1.0,
+        }
+    }
+}
+
+impl Telemetry {
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+pub(crate) fn configure_tracing(telemetry: &Telemetry) -> Result<()> {
+    if !telemetry.enabled {
+        return Ok(());
+    }
+    let config = trace::config()
+        .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(telemetry.sample_ratio))))
+        .with_resource(Resource::new(vec![KeyValue::new("service.name", telemetry.service_name.clone())]));
+    let tracer = match &telemetry.exporter {
+        TraceExporter::Otlp(endpoint) => opentelemetry_otlp::new_pipeline()
+            .tracing()
+            .with_exporter(opentelemetry_otlp::new_exporter().tonic().with_endpoint(endpoint.clone()))
+            .with_trace_config(config)
+            .install_batch(runtime::Tokio)
+            .map_err(|e| Error::new(format!("cannot create trace exporter: {}", e)))?,
+        TraceExporter::File(path) => {
+            let provider = TracerProvider::builder()
+                .with_config(config)
+                .with_batch_exporter(FileSpanExporter::open(path)?, runtime::Tokio)
+                .build();
+            let tracer = provider.tracer("teo");
+            global::set_tracer_provider(provider);
+            tracer
+        }
+    };
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    tracing_subscriber::registry()
+        .with(tracing_opentelemetry::layer().with_tracer(tracer))
+        .try_init()
+        .map_err(|e| Error::new(format!("cannot install tracing subscriber: {}", e)))
+}
+
+/// Export the spans which are still buffered.
+pub(crate) fn shutdown_tracing(telemetry: &Telemetry) {
+    if telemetry.enabled {
+        global::shutdown_tracer_provider();
+    }
+}
+
+/// The root span of a request, a child of the caller's span when `traceparent` is sent.
+pub(crate) fn request_span(headers: &HeaderMap, method: &str, path: &str, request_id: &str) -> Span {
+    let span = info_span!(
+        "request",
+        otel.name = %format!("{} {}", method, path),
+        http.method = method,
+        http.target = path,
+        http.status_code = field::Empty,
+        request_id = request_id,
+        handler = field::Empty,
+    );
+    span.set_parent(parent_context(headers));
+    span
+}
+
+fn parent_context(headers: &HeaderMap) -> Context {
+    global::get_text_map_propagator(|propagator| propagator.extract(&HeaderExtractor(headers)))
+}
+
+/// Record the matched handler on the request span.
+pub(crate) fn record_handler(handler_path: &str) {
+    Span::current().record("handler", handler_path);
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl<'a> Extractor for HeaderExtractor<'a> {
+
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|key| key.as_str()).collect()
+    }
+}
+
+#[derive(Debug)]
+struct FileSpanExporter {
+    file: File,
+}
+
+impl FileSpanExporter {
+
+    fn open(path: &PathBuf) -> Result<Self> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).map_err(|e| Error::new(format!("cannot create trace directory: {}", e)))?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path).map_err(|e| Error::new(format!("cannot open trace file {}: {}", path.display(), e)))?;
+        Ok(Self { file })
+    }
+}
+
+impl SpanExporter for FileSpanExporter {
+
+    fn export(&mut self, batch: Vec<SpanData>) -> BoxFuture<'static, ExportResult> {
+        let mut result = Ok(());
+        for span in batch {
+            let line = json!({
+                "traceId": span.span_context.trace_id().to_string(),
+                "spanId": span.span_context.span_id().to_string(),
+                "parentSpanId": span.parent_span_id.to_string(),
+                "name": span.name,
+                "kind": format!("{:?}", span.span_kind),
+                "startTimeUnixNano": unix_nanos(span.start_time),
+                "endTimeUnixNano": unix_nanos(span.end_time),
+                "attributes": span.attributes.iter().map(|kv| json!({ "key": kv.key.as_str(), "value": kv.value.to_string() })).collect::<Vec<_>>(),
+                "status": format!("{:?}", span.status),
+            });
+            if let Err(err) = writeln!(self.file, "{}", line) {
+                result = Err(TraceError::from(format!("cannot write span: {}", err)));
+            }
+        }
+        Box::pin(std::future::ready(result))
+    }
+}
+
+fn unix_nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or(0).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_http::header::{HeaderMap, HeaderName, HeaderValue};
+    use opentelemetry::{global, KeyValue};
+    use opentelemetry::trace::{Span as _, TraceContextExt, Tracer as _, TracerProvider as _};
+    use opentelemetry_sdk::propagation::TraceContextPropagator;
+    use opentelemetry_sdk::trace::TracerProvider;
+    use serde_json::Value as JsonValue;
+    use super::{parent_context, FileSpanExporter};
+
+    #[test]
+    fn traceparent_is_continued() {
+        global::set_text_map_propagator(TraceContextPropagator::new());
+        let mut headers = HeaderMap::new();
+        headers.insert(HeaderName::from_static("traceparent"), HeaderValue::from_static("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"));
+        let context = parent_context(&headers);
+        let span_context = context.span().span_context().clone();
+        assert_eq!(span_context.trace_id().to_string(), "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(span_context.span_id().to_string(), "00f067aa0ba902b7");
+        assert!(span_context.is_remote());
+        assert!(span_context.is_sampled());
+        headers.insert(HeaderName::from_static("traceparent"), HeaderValue::from_static("invalid"));
+        assert!(!parent_context(&headers).span().span_context().is_valid());
+        assert!(!parent_context(&HeaderMap::new()).span().span_context().is_valid());
+    }
+
+    #[test]
+    fn spans_are_written_as_json_lines() {
+        let path = std::env::temp_dir().join(format!("teo-telemetry-test-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let provider = TracerProvider::builder().with_simple_exporter(FileSpanExporter::open(&path).unwrap()).build();
+        let tracer = provider.tracer("test");
+        let mut span = tracer.start("work");
+        span.set_attribute(KeyValue::new("handler", "user.findMany"));
+        let trace_id = span.span_context().trace_id().to_string();
+        span.end();
+        drop(tracer);
+        drop(provider);
+        let content = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let lines: Vec<&str> = content.lines().collect();
+        assert_eq!(lines.len(), 1);
+        let span: JsonValue = serde_json::from_str(lines[0]).unwrap();
+        assert_eq!(span["name"], "work");
+        assert_eq!(span["traceId"], trace_id.as_str());
+        assert_eq!(span["parentSpanId"], "0000000000000000");
+        assert_eq!(span["attributes"][0]["key"], "handler");
+        assert_eq!(span["attributes"][0]["value"], "user.findMany");
+        let start: u128 = span["startTimeUnixNano"].as_str().unwrap().parse().unwrap();
+        let end: u128 = span["endTimeUnixNano"].as_str().unwrap().parse().unwrap();
+        assert!(start > 0 && end >= start);
+    }
+}
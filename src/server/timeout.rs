@@ -0,0 +1,79 @@
+use std::future::Future;
+use std::time::Instant;
+use teo_result::Result;
+use teo_runtime::connection::transaction;
+use crate::message::timeout_message;
+use crate::server::error::status_error;
+use crate::server::metrics::{record_transaction, TransactionOutcome};
+use crate::server::options::ServerOptions;
+
+/// Run `future` within the timeout of `handler_path`, counted from `started` so that reading
+/// the body and running the handler share it. `future` is dropped when the timeout is exceeded,
+/// which cancels the handler, and the transactions `transaction_ctx` still has open are aborted
+/// in the background. Statements which ran outside of a transaction are not undone. The outcome
+/// of `transaction_ctx` is recorded in the metrics.
+pub(super) async fn with_timeout<F, T>(options: &ServerOptions, handler_path: &Vec<String>, started: Instant, transaction_ctx: Option<&transaction::Ctx>, future: F) -> Result<T> where F: Future<Output = Result<T>> {
+    let result = match options.timeout_for(handler_path) {
+        Some(timeout) => match tokio::time::timeout_at((started + timeout).into(), future).await {
+            Ok(result) => result,
+            Err(_) => {
+                timeout_message(&handler_path.join("."), timeout);
+                if let Some(transaction_ctx) = transaction_ctx {
+                    record_transaction(&options.metrics, TransactionOutcome::Aborted);
+                    // the client is answered right away even when the database is what's stuck
+                    let transaction_ctx = transaction_ctx.clone();
+                    actix_web::rt::spawn(async move {
+                        let _ = transaction_ctx.abort().await;
+                    });
+                }
+                return Err(status_error(504, format!("request timed out after {}ms", timeout.as_millis())));
+            }
+        },
+        None => future.await,
+    };
+    if transaction_ctx.is_some() {
+        record_transaction(&options.metrics, if result.is_ok() { TransactionOutcome::Committed } else { TransactionOutcome::RolledBack });
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+    use crate::server::options::ServerOptions;
+    use super::with_timeout;
+
+    fn options() -> ServerOptions {
+        let mut options = ServerOptions::new();
+        options.set_handler_timeout("slow", Duration::from_millis(10));
+        options
+    }
+
+    #[tokio::test]
+    async fn slow_handlers_time_out() {
+        let result: teo_result::Result<()> = with_timeout(&options(), &vec!["slow".to_owned(), "find".to_owned()], Instant::now(), None, async {
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            Ok(())
+        }).await;
+        assert_eq!(result.unwrap_err().code, Some(504));
+    }
+
+    #[tokio::test]
+    async fn other_handlers_have_no_timeout() {
+        let result = with_timeout(&options(), &vec!["fast".to_owned()], Instant::now(), None, async {
+            tokio::time::sleep(Duration::from_millis(30)).await;
+            Ok(1)
+        }).await;
+        assert_eq!(result.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn time_spent_before_counts_against_the_timeout() {
+        let started = Instant::now() - Duration::from_millis(10);
+        let result = with_timeout(&options(), &vec!["slow".to_owned()], started, None, async {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+            Ok(1)
+        }).await;
+        assert_eq!(result.unwrap_err().code, Some(504));
+    }
+}
@@ -0,0 +1,185 @@
+use std::io::BufReader;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use actix_web::{web, HttpRequest, HttpResponse};
+use actix_web::http::header::LOCATION;
+use rustls::{RootCertStore, ServerConfig};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::WebPkiClientVerifier;
+use teo_result::{Error, Result};
+
+/// Where a PEM encoded certificate or key is loaded from.
+#[derive(Debug, Clone)]
+pub enum PemSource {
+    File(PathBuf),
+    /// The name of an environment variable which holds the PEM content.
+    Env(String),
+    Pem(String),
+}
+
+impl PemSource {
+
+    fn read(&self) -> Result<Vec<u8>> {
+        match self {
+            PemSource::File(path) => std::fs::read(path).map_err(|e| Error::new(format!("cannot read `{}`: {}", path.display(), e))),
+            PemSource::Env(name) => std::env::var(name).map(|v| v.into_bytes()).map_err(|_| Error::new(format!("environment variable `{}` is not set", name))),
+            PemSource::Pem(content) => Ok(content.clone().into_bytes()),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Tls {
+    pub certificate: PemSource,
+    pub private_key: PemSource,
+    /// CA certificates used to verify client certificates. Setting this enables mTLS.
+    pub client_ca: Option<PemSource>,
+    /// Reject clients which don't present a certificate. Only used when `client_ca` is set.
+    pub client_auth_required: bool,
+    /// Start a plain HTTP listener on this port which redirects every request to HTTPS.
+    pub redirect_http_port: Option<u16>,
+    /// The host redirects point to. Defaults to the bound address, which must then be a
+    /// specific one. The `Host` header of the request is never used.
+    pub redirect_host: Option<String>,
+}
+
+impl Tls {
+
+    pub fn new(certificate: PemSource, private_key: PemSource) -> Self {
+        Self {
+            certificate,
+            private_key,
+            client_ca: None,
+            client_auth_required: true,
+            redirect_http_port: None,
+            redirect_host: None,
+        }
+    }
+
+    /// The host HTTP requests are redirected to when the server is bound to `bind_host`.
+    pub(super) fn redirect_host(&self, bind_host: &str) -> Result<String> {
+        if let Some(redirect_host) = &self.redirect_host {
+            return Ok(redirect_host.clone());
+        }
+        match bind_host.parse::<IpAddr>() {
+            Ok(ip) if ip.is_unspecified() => Err(Error::new(format!("`redirect_host` is required to redirect to HTTPS when binding to {}", bind_host))),
+            Ok(IpAddr::V6(ip)) => Ok(format!("[{}]", ip)),
+            _ => Ok(bind_host.to_owned()),
+        }
+    }
+
+    pub(super) fn server_config(&self) -> Result<ServerConfig> {
+        let certificates = read_certificates(&self.certificate)?;
+        let private_key = read_private_key(&self.private_key)?;
+        let builder = ServerConfig::builder();
+        let builder = if let Some(client_ca) = &self.client_ca {
+            let mut roots = RootCertStore::empty();
+            for certificate in read_certificates(client_ca)? {
+                roots.add(certificate).map_err(|e| Error::new(format!("invalid client CA certificate: {}", e)))?;
+            }
+            let verifier_builder = WebPkiClientVerifier::builder(Arc::new(roots));
+            let verifier_builder = if self.client_auth_required {
+                verifier_builder
+            } else {
+                verifier_builder.allow_unauthenticated()
+            };
+            let verifier = verifier_builder.build().map_err(|e| Error::new(format!("invalid client CA: {}", e)))?;
+            builder.with_client_cert_verifier(verifier)
+        } else {
+            builder.with_no_client_auth()
+        };
+        builder.with_single_cert(certificates, private_key).map_err(|e| Error::new(format!("invalid TLS certificate or key: {}", e)))
+    }
+}
+
+fn read_certificates(source: &PemSource) -> Result<Vec<CertificateDer<'static>>> {
+    let content = source.read()?;
+    let certificates = rustls_pemfile::certs(&mut BufReader::new(content.as_slice()))
+        .collect::<std::result::Result<Vec<CertificateDer<'static>>, std::io::Error>>()
+        .map_err(|e| Error::new(format!("invalid PEM certificate: {}", e)))?;
+    if certificates.is_empty() {
+        Err(Error::new("no certificate found in PEM"))?
+    }
+    Ok(certificates)
+}
+
+fn read_private_key(source: &PemSource) -> Result<PrivateKeyDer<'static>> {
+    let content = source.read()?;
+    match rustls_pemfile::private_key(&mut BufReader::new(content.as_slice())) {
+        Ok(Some(key)) => Ok(key),
+        Ok(None) => Err(Error::new("no private key found in PEM")),
+        Err(e) => Err(Error::new(format!("invalid PEM private key: {}", e))),
+    }
+}
+
+/// Answer every plain HTTP request with a permanent redirect to the same path on `host` over HTTPS.
+pub(super) fn redirect_to_https(host: String, https_port: u16) -> actix_web::Route {
+    web::route().to(move |http_request: HttpRequest| {
+        let location = redirect_location(&host, https_port, http_request.uri().path_and_query().map(|p| p.as_str()).unwrap_or("/"));
+        async move {
+            HttpResponse::PermanentRedirect().insert_header((LOCATION, location)).finish()
+        }
+    })
+}
+
+fn redirect_location(host: &str, https_port: u16, path_and_query: &str) -> String {
+    let port = if https_port == 443 { "".to_owned() } else { format!(":{}", https_port) };
+    format!("https://{}{}{}", host, port, path_and_query)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{redirect_location, PemSource, Tls};
+
+    fn tls() -> Tls {
+        Tls::new(PemSource::Pem(String::new()), PemSource::Pem(String::new()))
+    }
+
+    /// A certificate and key for `localhost`, generated for each test.
+    fn self_signed() -> (String, String) {
+        let certificate = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        (certificate.serialize_pem().unwrap(), certificate.serialize_private_key_pem())
+    }
+
+    #[test]
+    fn server_config_is_built_from_pem() {
+        let (certificate, private_key) = self_signed();
+        let tls = Tls::new(PemSource::Pem(certificate), PemSource::Pem(private_key));
+        assert!(tls.server_config().is_ok());
+    }
+
+    #[test]
+    fn client_certificates_are_verified_against_the_client_ca() {
+        let (certificate, private_key) = self_signed();
+        let (client_ca, _) = self_signed();
+        let mut tls = Tls::new(PemSource::Pem(certificate), PemSource::Pem(private_key));
+        tls.client_ca = Some(PemSource::Pem(client_ca));
+        assert!(tls.server_config().is_ok());
+        tls.client_auth_required = false;
+        assert!(tls.server_config().is_ok());
+        tls.client_ca = Some(PemSource::Pem(String::new()));
+        assert!(tls.server_config().is_err());
+    }
+
+    #[test]
+    fn redirect_host_defaults_to_the_bound_address() {
+        assert_eq!(tls().redirect_host("127.0.0.1").unwrap(), "127.0.0.1");
+        assert_eq!(tls().redirect_host("::1").unwrap(), "[::1]");
+        assert_eq!(tls().redirect_host("example.com").unwrap(), "example.com");
+        assert!(tls().redirect_host("0.0.0.0").is_err());
+    }
+
+    #[test]
+    fn configured_redirect_host_wins() {
+        let mut tls = tls();
+        tls.redirect_host = Some("example.com".to_owned());
+        assert_eq!(tls.redirect_host("0.0.0.0").unwrap(), "example.com");
+    }
+
+    #[test]
+    fn redirect_location_keeps_path_and_query() {
+        assert_eq!(redirect_location("example.com", 443, "/a?b=1"), "https://example.com/a?b=1");
+        assert_eq!(redirect_location("example.com", 8443, "/"), "https://example.com:8443/");
+    }
+}
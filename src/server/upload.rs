@@ -0,0 +1,147 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use actix_http::HttpMessage;
+use actix_web::HttpRequest;
+use teo_result::{Error, Result};
+use teo_teon::types::file::File;
+use uuid::Uuid;
+use crate::server::storage::FileStorage;
+
+/// Files written while parsing a multipart body. They live in the request extensions and are
+/// removed when the request is dropped, unless the handler has moved them away.
+#[derive(Debug, Default)]
+pub(crate) struct UploadedFiles(Vec<PathBuf>);
+
+impl Drop for UploadedFiles {
+
+    fn drop(&mut self) {
+        for path in &self.0 {
+            if path.is_file() {
+                let _ = std::fs::remove_file(path);
+            }
+        }
+    }
+}
+
+/// Files put into the file storage while parsing a multipart body. They are deleted from the
+/// storage when the request is dropped, unless the handler succeeded.
+#[derive(Debug)]
+pub(crate) struct StoredFiles {
+    storage: Arc<dyn FileStorage>,
+    keys: Vec<String>,
+    keep: bool,
+}
+
+impl Drop for StoredFiles {
+
+    fn drop(&mut self) {
+        if self.keep || self.keys.is_empty() {
+            return;
+        }
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        let storage = self.storage.clone();
+        let keys = std::mem::take(&mut self.keys);
+        runtime.spawn(async move {
+            for key in keys {
+                let _ = storage.delete(&key).await;
+            }
+        });
+    }
+}
+
+pub(crate) fn track_stored_file(http_request: &HttpRequest, storage: Arc<dyn FileStorage>, key: String) {
+    let mut extensions = http_request.extensions_mut();
+    if let Some(stored_files) = extensions.get_mut::<StoredFiles>() {
+        stored_files.keys.push(key);
+    } else {
+        extensions.insert(StoredFiles { storage, keys: vec![key], keep: false });
+    }
+}
+
+/// Keep the files stored for this request, called once its handler succeeded.
+pub(crate) fn keep_stored_files(http_request: &HttpRequest) {
+    if let Some(stored_files) = http_request.extensions_mut().get_mut::<StoredFiles>() {
+        stored_files.keep = true;
+    }
+}
+
+pub(crate) fn track_upload(http_request: &HttpRequest, path: PathBuf) {
+    let mut extensions = http_request.extensions_mut();
+    if let Some(uploaded_files) = extensions.get_mut::<UploadedFiles>() {
+        uploaded_files.0.push(path);
+    } else {
+        extensions.insert(UploadedFiles(vec![path]));
+    }
+}
+
+/// A unique file name for an upload. Only a sane extension of the client supplied filename
+/// is kept.
+pub(crate) fn upload_file_name(filename: &str) -> String {
+    let extension = Path::new(filename)
+        .extension()
+        .and_then(|e| e.to_str())
+        .filter(|e| e.len() <= 16 && e.chars().all(|c| c.is_ascii_alphanumeric()));
+    match extension {
+        Some(extension) => format!("{}.{}", Uuid::new_v4(), extension),
+        None => Uuid::new_v4().to_string(),
+    }
+}
+
+/// Move an uploaded file out of the upload directory so that it's kept after the request.
+pub fn persist_upload(file: &File, destination: impl AsRef<Path>) -> Result<()> {
+    let destination = destination.as_ref();
+    if let Some(parent) = destination.parent() {
+        std::fs::create_dir_all(parent).map_err(|e| Error::new(format!("cannot persist upload: {}", e)))?;
+    }
+    if std::fs::rename(&file.filepath, destination).is_err() {
+        // rename doesn't work across file systems
+        std::fs::copy(&file.filepath, destination).map_err(|e| Error::new(format!("cannot persist upload: {}", e)))?;
+        let _ = std::fs::remove_file(&file.filepath);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+    use actix_web::test::TestRequest;
+    use actix_web::web::Bytes;
+    use crate::server::storage::{FileStorage, MemoryStorage};
+    use super::{keep_stored_files, track_stored_file, upload_file_name};
+
+    async fn stored_after_request(keep: bool) -> bool {
+        let storage = Arc::new(MemoryStorage::new());
+        storage.put("a.txt", None, Bytes::from_static(b"a")).await.unwrap();
+        let http_request = TestRequest::default().to_http_request();
+        track_stored_file(&http_request, storage.clone(), "a.txt".to_owned());
+        if keep {
+            keep_stored_files(&http_request);
+        }
+        drop(http_request);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        storage.get("a.txt").await.unwrap().is_some()
+    }
+
+    #[tokio::test]
+    async fn stored_files_are_deleted_unless_kept() {
+        assert!(!stored_after_request(false).await);
+        assert!(stored_after_request(true).await);
+    }
+
+    #[test]
+    fn only_a_sane_extension_is_kept() {
+        assert!(upload_file_name("photo.jpeg").ends_with(".jpeg"));
+        assert!(!upload_file_name("../../etc/passwd").contains('/'));
+        assert!(!upload_file_name("archive.tar.g z").contains(' '));
+        assert!(!upload_file_name("noextension").contains('.'));
+        assert!(!upload_file_name("file.averyveryverylongextension").contains('.'));
+    }
+
+    #[test]
+    fn names_are_unique() {
+        assert_ne!(upload_file_name("a.txt"), upload_file_name("a.txt"));
+    }
+}
@@ -0,0 +1,174 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use actix_web::{HttpRequest, HttpResponse, web};
+use actix_web::http::header::{CONNECTION, UPGRADE};
+use actix_ws::{AggregatedMessage, Session};
+use async_trait::async_trait;
+use indexmap::IndexMap;
+use teo_result::{Error, Result};
+use teo_runtime::connection;
+use teo_runtime::connection::transaction;
+use teo_runtime::handler::r#match::HandlerMatch;
+use teo_runtime::request::Request;
+use teo_teon::Value;
+use tokio::sync::OwnedSemaphorePermit;
+use uuid::Uuid;
+use crate::server::encoding::BodyEncoding;
+use crate::server::error::status_error;
+
+/// The header set by the innermost middleware when the namespace middleware stack lets a
+/// WebSocket handshake through. It's never sent to clients.
+pub(crate) const WEBSOCKET_ACCEPTED_HEADER: &str = "x-teo-websocket-accepted";
+
+/// A WebSocket endpoint. It's registered for the path of a `GET` handler in the schema and
+/// takes over requests to that path which ask for a WebSocket upgrade.
+#[async_trait(?Send)]
+pub trait WebSocketHandler: Send + Sync {
+
+    async fn on_open(&self, _connection: &WebSocketConnection) -> Result<()> {
+        Ok(())
+    }
+
+    async fn on_message(&self, connection: &WebSocketConnection, message: Value) -> Result<()>;
+
+    async fn on_close(&self, _connection: &WebSocketConnection) { }
+}
+
+/// A connected client. Messages are sent as JSON text frames. Clones refer to the same
+/// connection, so one can be moved into a task which pushes messages.
+#[derive(Clone)]
+pub struct WebSocketConnection {
+    id: String,
+    session: Session,
+    request: Request,
+    handler_match: HandlerMatch,
+    conn_ctx: connection::Ctx,
+    data: Arc<Mutex<BTreeMap<String, Value>>>,
+}
+
+impl WebSocketConnection {
+
+    pub fn id(&self) -> &str {
+        self.id.as_str()
+    }
+
+    pub fn request(&self) -> &Request {
+        &self.request
+    }
+
+    pub fn handler_match(&self) -> &HandlerMatch {
+        &self.handler_match
+    }
+
+    pub fn transaction_ctx(&self) -> transaction::Ctx {
+        transaction::Ctx::new(self.conn_ctx.clone())
+    }
+
+    /// Read a value stored for this connection.
+    pub fn get(&self, key: &str) -> Option<Value> {
+        self.data.lock().unwrap().get(key).cloned()
+    }
+
+    /// Store a value for the lifetime of this connection.
+    pub fn set(&self, key: &str, value: Value) {
+        self.data.lock().unwrap().insert(key.to_owned(), value);
+    }
+
+    pub async fn send(&self, message: &Value) -> Result<()> {
+        self.send_json(&serde_json::Value::try_from(message)?).await
+    }
+
+    pub async fn send_json(&self, message: &JsonValue) -> Result<()> {
+        let text = serde_json::to_string(message).map_err(|e| Error::new(format!("{}", e)))?;
+        self.session.clone().text(text).await.map_err(|_| Error::new("websocket connection is closed"))
+    }
+
+    pub async fn close(&self) {
+        let _ = self.session.clone().close(None).await;
+    }
+
+    async fn send_error(&self, error: &Error) {
+        let value: Value = error.into();
+        let mut map = IndexMap::new();
+        map.insert("error".to_owned(), value);
+        let _ = self.send(&Value::Dictionary(map)).await;
+    }
+}
+
+pub(crate) fn is_websocket_upgrade(http_request: &HttpRequest) -> bool {
+    let upgrade = http_request.headers().get(UPGRADE).and_then(|u| u.to_str().ok()).map(|u| u.eq_ignore_ascii_case("websocket")).unwrap_or(false);
+    let connection = http_request.headers().get(CONNECTION).and_then(|c| c.to_str().ok()).map(|c| c.to_ascii_lowercase().contains("upgrade")).unwrap_or(false);
+    upgrade && connection
+}
+
+/// Finish the handshake and serve the connection on the current worker. The concurrency
+/// `permit` of the handler is held until the connection is closed.
+pub(crate) fn upgrade(
+    http_request: &HttpRequest,
+    payload: web::Payload,
+    handler: Arc<dyn WebSocketHandler>,
+    request: Request,
+    handler_match: HandlerMatch,
+    conn_ctx: connection::Ctx,
+    ping_interval: Duration,
+    permit: Option<OwnedSemaphorePermit>,
+) -> Result<HttpResponse> {
+    let (response, session, stream) = actix_ws::handle(http_request, payload).map_err(|e| status_error(400, format!("{}", e)))?;
+    let connection = WebSocketConnection {
+        id: Uuid::new_v4().to_string(),
+        session,
+        request,
+        handler_match,
+        conn_ctx,
+        data: Arc::new(Mutex::new(BTreeMap::new())),
+    };
+    actix_web::rt::spawn(async move {
+        let _permit = permit;
+        let mut stream = stream.aggregate_continuations();
+        if let Err(err) = handler.on_open(&connection).await {
+            connection.send_error(&err).await;
+            connection.close().await;
+            return
+        }
+        let mut ping = tokio::time::interval(ping_interval);
+        let mut last_seen = Instant::now();
+        loop {
+            tokio::select! {
+                message = stream.recv() => {
+                    last_seen = Instant::now();
+                    let decoded = match message {
+                        Some(Ok(AggregatedMessage::Text(text))) => BodyEncoding::Json.decode_teon(text.as_bytes()),
+                        Some(Ok(AggregatedMessage::Binary(bytes))) => BodyEncoding::MessagePack.decode_teon(&bytes),
+                        Some(Ok(AggregatedMessage::Ping(bytes))) => {
+                            if connection.session.clone().pong(&bytes).await.is_err() {
+                                break
+                            }
+                            continue
+                        }
+                        Some(Ok(AggregatedMessage::Pong(_))) => continue,
+                        Some(Ok(AggregatedMessage::Close(_))) | Some(Err(_)) | None => break,
+                    };
+                    let result = match decoded {
+                        Ok(value) => handler.on_message(&connection, value).await,
+                        Err(err) => Err(err),
+                    };
+                    if let Err(err) = result {
+                        connection.send_error(&err).await;
+                    }
+                }
+                _ = ping.tick() => {
+                    if last_seen.elapsed() > ping_interval * 2 {
+                        break
+                    }
+                    if connection.session.clone().ping(b"").await.is_err() {
+                        break
+                    }
+                }
+            }
+        }
+        handler.on_close(&connection).await;
+        connection.close().await;
+    });
+    Ok(response)
+}
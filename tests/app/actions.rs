@@ -0,0 +1,28 @@
+use reqwest::blocking::Client;
+use reqwest::header::ALLOW;
+use reqwest::StatusCode;
+use serde_json::{json, Value};
+use crate::{start, url};
+
+#[test]
+fn registered_actions_are_served_for_every_model() {
+    start();
+    let client = Client::new();
+    let res = client.post(url("/Support/echoArgs")).json(&json!({ "message": "hello" })).send().unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let body: Value = res.json().unwrap();
+    assert_eq!(body["data"]["message"], "hello");
+}
+
+#[test]
+fn disabled_actions_are_not_found() {
+    start();
+    let client = Client::new();
+    let res = client.post(url("/Support/deleteMany")).json(&json!({})).send().unwrap();
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    let res = client.request(reqwest::Method::OPTIONS, url("/Support/deleteMany")).send().unwrap();
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    let res = client.request(reqwest::Method::OPTIONS, url("/Support/findMany")).send().unwrap();
+    assert_eq!(res.status(), StatusCode::NO_CONTENT);
+    assert_eq!(res.headers().get(ALLOW).unwrap(), "POST, OPTIONS");
+}
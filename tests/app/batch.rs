@@ -0,0 +1,51 @@
+use reqwest::blocking::Client;
+use reqwest::header::ALLOW;
+use reqwest::StatusCode;
+use serde_json::{json, Value};
+use crate::{start, url};
+
+#[test]
+fn operations_are_rolled_back_on_the_first_error() {
+    start();
+    let client = Client::new();
+    let res = client.post(url("/batch")).json(&json!({
+        "operations": [
+            { "model": "Support", "action": "create", "args": { "create": { "string": "rolled back" } } },
+            { "model": "Support", "action": "update", "args": { "where": { "id": 999999 }, "update": { "int": 1 } } },
+        ]
+    })).send().unwrap();
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    let body: Value = res.json().unwrap();
+    assert_eq!(body["index"], 1);
+    let res = client.post(url("/Support/findMany")).json(&json!({ "where": { "string": "rolled back" } })).send().unwrap();
+    let body: Value = res.json().unwrap();
+    assert_eq!(body["data"], json!([]));
+}
+
+#[test]
+fn results_are_returned_in_order() {
+    start();
+    let client = Client::new();
+    let res = client.post(url("/batch")).json(&json!({
+        "operations": [
+            { "model": "Support", "action": "create", "args": { "create": { "string": "first" } } },
+            { "model": "Support", "action": "create", "args": { "create": { "string": "second" } } },
+        ]
+    })).send().unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let body: Value = res.json().unwrap();
+    assert_eq!(body["data"][0]["string"], "first");
+    assert_eq!(body["data"][1]["string"], "second");
+}
+
+#[test]
+fn other_methods_are_answered_with_allow() {
+    start();
+    let client = Client::new();
+    let res = client.request(reqwest::Method::OPTIONS, url("/batch")).send().unwrap();
+    assert_eq!(res.status(), StatusCode::NO_CONTENT);
+    assert_eq!(res.headers().get(ALLOW).unwrap(), "POST, OPTIONS");
+    let res = client.get(url("/batch")).send().unwrap();
+    assert_eq!(res.status(), StatusCode::METHOD_NOT_ALLOWED);
+    assert_eq!(res.headers().get(ALLOW).unwrap(), "POST, OPTIONS");
+}
@@ -0,0 +1,70 @@
+//! Tests of server options which are set from Rust. The app context is global, so these run in
+//! a test binary of their own, sharing one app started on a background thread.
+
+mod websocket;
+mod batch;
+mod actions;
+
+use std::net::TcpStream;
+use std::sync::Once;
+use std::thread;
+use std::time::{Duration, Instant};
+use teo::prelude::*;
+use teo::server::options::ServerOptions;
+use teo::server::rate_limit::ConcurrencyLimit;
+
+pub(crate) const PORT: u16 = 4061;
+
+static START: Once = Once::new();
+
+/// Start the app once and wait until it accepts connections.
+pub(crate) fn start() {
+    START.call_once(|| {
+        thread::spawn(|| {
+            let runtime = tokio::runtime::Runtime::new().unwrap();
+            runtime.block_on(async {
+                std::env::set_var("TEO_ENV", "test");
+                let schema = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/app/schema.teo");
+                let argv = vec!["teo".to_owned(), "-s".to_owned(), schema.to_owned(), "serve".to_owned()];
+                let app = App::new_with_entrance_and_runtime_version(Some(Entrance::CLI), None, Some(argv)).unwrap();
+                define_middlewares(app.main_namespace_mut());
+                configure(app.server_options_mut());
+                app.run().await.unwrap();
+            });
+        });
+        let started = Instant::now();
+        while TcpStream::connect(("127.0.0.1", PORT)).is_err() {
+            assert!(started.elapsed() < Duration::from_secs(30), "the app didn't start");
+            thread::sleep(Duration::from_millis(100));
+        }
+    });
+}
+
+pub(crate) fn url(path: &str) -> String {
+    format!("http://127.0.0.1:{}{}", PORT, path)
+}
+
+/// Requests with `x-reject` are refused by a namespace middleware.
+fn define_middlewares(namespace: &mut Namespace) {
+    namespace.define_middleware("rejectFlagged", |_arguments: teo::prelude::Arguments| async move {
+        Ok(middleware_wrap_fn(|ctx: request::Ctx, next: &'static dyn Next| async move {
+            if ctx.request().headers().get("x-reject").is_some() {
+                let mut error = Error::new("rejected");
+                error.code = Some(401);
+                return Err(error);
+            }
+            next.call(ctx).await
+        }))
+    });
+}
+
+fn configure(options: &mut ServerOptions) {
+    options.batch_path = Some("/batch".to_owned());
+    options.model_actions.register("echoArgs", |ctx: request::Ctx| async move {
+        Ok(Response::data(ctx.body().clone()))
+    });
+    options.model_actions.disable("Support", "deleteMany");
+    options.add_websocket_handler("echo", websocket::Echo);
+    options.set_concurrency_limit("echo", ConcurrencyLimit::new(1));
+    options.websocket_ping_interval = Duration::from_millis(200);
+}
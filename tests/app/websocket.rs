@@ -0,0 +1,98 @@
+use std::time::Duration;
+use async_trait::async_trait;
+use futures_util::{SinkExt, StreamExt};
+use teo::prelude::*;
+use teo::server::websocket::{WebSocketConnection, WebSocketHandler};
+use tokio_tungstenite::connect_async;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::Message;
+use crate::{start, PORT};
+
+pub(crate) struct Echo;
+
+#[async_trait(?Send)]
+impl WebSocketHandler for Echo {
+
+    async fn on_message(&self, connection: &WebSocketConnection, message: Value) -> Result<()> {
+        connection.send(&message).await
+    }
+}
+
+fn echo_request() -> tokio_tungstenite::tungstenite::handshake::client::Request {
+    format!("ws://127.0.0.1:{}/echo", PORT).into_client_request().unwrap()
+}
+
+/// The tests share one handler limited to one connection, so they don't run in parallel.
+static SERIAL: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+type Socket = tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>;
+
+/// Connect once the connection of the previous test is released.
+async fn connect() -> Socket {
+    for _ in 0..20 {
+        if let Ok((socket, _)) = connect_async(echo_request()).await {
+            return socket;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    panic!("cannot connect to the echo handler")
+}
+
+#[tokio::test]
+async fn messages_are_echoed() {
+    start();
+    let _serial = SERIAL.lock().await;
+    let mut socket = connect().await;
+    socket.send(Message::Text("{\"a\":1}".to_owned())).await.unwrap();
+    let reply = loop {
+        match socket.next().await.unwrap().unwrap() {
+            Message::Text(text) => break text,
+            _ => continue,
+        }
+    };
+    assert_eq!(serde_json::from_str::<serde_json::Value>(&reply).unwrap(), serde_json::json!({ "a": 1 }));
+    socket.close(None).await.unwrap();
+}
+
+#[tokio::test]
+async fn middlewares_can_reject_the_handshake() {
+    start();
+    let _serial = SERIAL.lock().await;
+    let mut request = echo_request();
+    request.headers_mut().insert("x-reject", "true".parse().unwrap());
+    match connect_async(request).await {
+        Err(tokio_tungstenite::tungstenite::Error::Http(response)) => assert_eq!(response.status(), 401),
+        other => panic!("expect the handshake to be rejected, got {:?}", other.map(|(_, response)| response.status())),
+    }
+}
+
+#[tokio::test]
+async fn silent_clients_are_disconnected() {
+    start();
+    let _serial = SERIAL.lock().await;
+    let mut socket = connect().await;
+    // pongs are only sent while reading, the server hears nothing for longer than two intervals
+    tokio::time::sleep(Duration::from_millis(800)).await;
+    let closed = tokio::time::timeout(Duration::from_secs(2), async {
+        loop {
+            match socket.next().await {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => continue,
+            }
+        }
+    }).await;
+    assert!(closed.is_ok());
+}
+
+#[tokio::test]
+async fn open_connections_count_against_the_concurrency_limit() {
+    start();
+    let _serial = SERIAL.lock().await;
+    let mut first = connect().await;
+    match connect_async(echo_request()).await {
+        Err(tokio_tungstenite::tungstenite::Error::Http(response)) => assert_eq!(response.status(), 503),
+        other => panic!("expect the handshake to be refused, got {:?}", other.map(|(_, response)| response.status())),
+    }
+    first.close(None).await.unwrap();
+    connect().await;
+}
@@ -0,0 +1,131 @@
+use test_helpers::*;
+
+#[before_all]
+#[after_all]
+mod test {
+    use std::sync::Mutex;
+    use reqwest::blocking::Client;
+    use reqwest::header::{ACCEPT, ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_REQUEST_METHOD, ALLOW, CONTENT_TYPE, ORIGIN, VARY};
+    use reqwest::StatusCode;
+    use serde_json::{json, Value};
+    use crate::lib::ExecutionHandle;
+    use crate::{assert_json, matcher};
+    use once_cell::sync::Lazy;
+
+    static HANDLE: Lazy<Mutex<ExecutionHandle>> = Lazy::new(|| {
+        Mutex::new(ExecutionHandle::new())
+    });
+    static PORT: i32 = 4060;
+
+    fn before_all() {
+        HANDLE.lock().unwrap().execute(file!(), "serve");
+    }
+
+    fn after_all() {
+        HANDLE.lock().unwrap().exit();
+    }
+
+    fn url(path: &str) -> String {
+        format!("http://127.0.0.1:{}{}", PORT, path)
+    }
+
+    #[test]
+    fn any_origin_is_allowed_by_default() {
+        let client = Client::new();
+        let res = client.post(url("/Support/create"))
+            .header(ORIGIN, "https://example.com")
+            .json(&json!({ "create": {} }))
+            .send().unwrap();
+        assert!(res.status().is_success());
+        assert_eq!(res.headers().get(ACCESS_CONTROL_ALLOW_ORIGIN).unwrap(), "*");
+        assert!(res.headers().get(VARY).is_none());
+    }
+
+    #[test]
+    fn json_bodies_are_limited() {
+        let client = Client::new();
+        let res = client.post(url("/Support/create"))
+            .json(&json!({ "create": { "string": "a".repeat(300_000) } }))
+            .send().unwrap();
+        assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[test]
+    fn message_pack_bodies() {
+        let client = Client::new();
+        let body = rmp_serde::to_vec_named(&json!({ "create": { "string": "msgpack", "int": 5 } })).unwrap();
+        let res = client.post(url("/Support/create"))
+            .header(CONTENT_TYPE, "application/msgpack")
+            .header(ACCEPT, "application/json")
+            .body(body)
+            .send().unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let body: Value = res.json().unwrap();
+        assert_json!(body, matcher!({
+            "data": {
+                "id": ignore,
+                "string": "msgpack",
+                "int": 5,
+            }
+        }));
+    }
+
+    #[test]
+    fn message_pack_responses() {
+        let client = Client::new();
+        let res = client.post(url("/Support/create"))
+            .header(ACCEPT, "application/msgpack")
+            .json(&json!({ "create": { "string": "packed" } }))
+            .send().unwrap();
+        assert_eq!(res.headers().get(CONTENT_TYPE).unwrap(), "application/msgpack");
+        let body: Value = rmp_serde::from_slice(&res.bytes().unwrap()).unwrap();
+        assert_eq!(body["data"]["string"], "packed");
+    }
+
+    #[test]
+    fn other_methods_are_not_allowed() {
+        let client = Client::new();
+        let res = client.delete(url("/Support/create")).send().unwrap();
+        assert_eq!(res.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(res.headers().get(ALLOW).unwrap(), "POST, OPTIONS");
+        let res = client.get(url("/Support/unknown/path")).send().unwrap();
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn preflights_list_the_methods_of_the_path() {
+        let client = Client::new();
+        let res = client.request(reqwest::Method::OPTIONS, url("/Support/create"))
+            .header(ORIGIN, "https://example.com")
+            .header(ACCESS_CONTROL_REQUEST_METHOD, "POST")
+            .send().unwrap();
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        assert_eq!(res.headers().get(ACCESS_CONTROL_ALLOW_METHODS).unwrap(), "POST, OPTIONS");
+        assert_eq!(res.headers().get(ACCESS_CONTROL_ALLOW_ORIGIN).unwrap(), "*");
+    }
+
+    #[test]
+    fn request_ids() {
+        let client = Client::new();
+        let res = client.post(url("/Support/create")).header("x-request-id", "abc-123").json(&json!({ "create": {} })).send().unwrap();
+        assert_eq!(res.headers().get("x-request-id").unwrap(), "abc-123");
+        let res = client.post(url("/Support/create")).json(&json!({ "create": {} })).send().unwrap();
+        assert!(!res.headers().get("x-request-id").unwrap().is_empty());
+        let res = client.post(url("/Support/unknown")).header("x-request-id", "abc-456").json(&json!({})).send().unwrap();
+        let body: Value = res.json().unwrap();
+        assert_eq!(body["requestId"], "abc-456");
+    }
+
+    #[test]
+    fn health_probes() {
+        let client = Client::new();
+        let res = client.get(url("/healthz")).send().unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let body: Value = res.json().unwrap();
+        assert_json!(body, matcher!({ "status": "ok" }));
+        let res = client.get(url("/readyz")).send().unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let body: Value = res.json().unwrap();
+        assert_json!(body, matcher!({ "status": "ok", "namespaces": { "main": "ok" } }));
+    }
+}